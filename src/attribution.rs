@@ -0,0 +1,363 @@
+/* Working out which invite a new member joined through. Discord does not tell
+ * us, so we diff the guild's live invites against the last snapshot we stored:
+ * the invite whose use count went up is the one that was used. Joins of the
+ * same guild must be attributed one at a time (see `GuildLocks`), otherwise
+ * two handlers can both claim the same use. */
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::store::{Confidence, LiveInvite, MappingStore, StoreResult, StoredInvite};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attribution {
+    /// The member joined through this invite, either `Certain`ly or because
+    /// it was `UsedUp`.
+    Invite { invite: StoredInvite, confidence: Confidence },
+    /// Several invites were used since the last snapshot, e.g. because
+    /// members joined at the same time or while we were offline. The codes of
+    /// every candidate are listed; none of them is assigned.
+    Ambiguous { candidates: Vec<String> },
+    /// No invite we know of was used: the member came in through a vanity
+    /// URL, server discovery, or an invite created and used up while we
+    /// were not watching.
+    Unknown,
+}
+
+/// Per-guild locks making sure the joins of a guild are attributed one at a
+/// time, while joins in different guilds are handled in parallel. Each lock
+/// guards some per-guild state `T`, such as the joins waiting to be attributed.
+pub struct GuildLocks<T = ()>(Mutex<HashMap<u64, Arc<AsyncMutex<T>>>>);
+
+impl Attribution {
+    pub fn confidence(&self) -> Confidence {
+        match self {
+            Attribution::Invite { confidence, .. } => *confidence,
+            Attribution::Ambiguous { .. } => Confidence::Ambiguous,
+            Attribution::Unknown => Confidence::Unknown,
+        }
+    }
+}
+
+impl<T> Default for GuildLocks<T> {
+    fn default() -> Self {
+        GuildLocks(Mutex::new(HashMap::new()))
+    }
+}
+
+impl<T: Default> GuildLocks<T> {
+    pub async fn lock(&self, guild_id: u64) -> OwnedMutexGuard<T> {
+        let lock = {
+            let mut locks = self.0.lock().unwrap_or_else(|p| p.into_inner());
+            locks.entry(guild_id).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+impl<T> GuildLocks<T> {
+    /// Wait until no guild's lock is held and take them all, so nothing is
+    /// being attributed, e.g. before shutting down. Guilds locked for the
+    /// first time meanwhile are not included.
+    pub async fn lock_all(&self) -> Vec<OwnedMutexGuard<T>> {
+        let locks = self.0.lock().unwrap_or_else(|p| p.into_inner()).values().cloned().collect::<Vec<_>>();
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in locks {
+            guards.push(lock.lock_owned().await);
+        }
+        guards
+    }
+}
+
+/// Diff the guild's `live` invites against the stored snapshot and work out
+/// which invite the member who just joined used.
+///
+/// Every use count that changed is recorded, except when all new uses are on
+/// a single invite: then only one use is consumed, so members who joined
+/// through that invite at the same time are each attributed in turn. Invites
+/// that have disappeared are forgotten.
+pub fn attribute_join(store: &dyn MappingStore, guild_id: u64, live: &[LiveInvite]) -> StoreResult<Attribution> {
+    let stored = store.list_guild(guild_id)?;
+
+    // Invites that gained uses since the snapshot, with how many they gained
+    let mut used = Vec::<(StoredInvite, u64)>::new();
+    for inv in live {
+        match stored.iter().find(|s| s.code == inv.code) {
+            Some(s) if inv.uses > s.uses => used.push((s.clone(), inv.uses - s.uses)),
+            Some(_) => {}
+            None => {
+                // We missed this invite being created. Nothing is linked to
+                // it, but it may still be the one that was used. Any earlier
+                // uses happened before we knew about it.
+                store.upsert_invite(guild_id, inv)?;
+                if inv.uses > 0 {
+                    let invite = StoredInvite {
+                        code: inv.code.clone(),
+                        guild_id,
+                        uses: inv.uses - 1,
+                        max_uses: inv.max_uses,
+                        inviter: inv.inviter,
+                        created_at: Some(inv.created_at),
+                        ..Default::default()
+                    };
+                    used.push((invite, 1));
+                }
+            }
+        }
+    }
+
+    // Invites that are gone. Discord deletes an invite once it reaches its
+    // max uses, so those that were one use away from that were used up.
+    let mut used_up = Vec::<StoredInvite>::new();
+    for s in stored.iter().filter(|s| !live.iter().any(|inv| inv.code == s.code)) {
+        if s.max_uses > 0 && s.uses + 1 >= s.max_uses {
+            used_up.push(s.clone());
+        }
+        store.remove_invite(&s.code)?;
+    }
+
+    match (used.as_slice(), used_up.as_slice()) {
+        ([], []) => Ok(Attribution::Unknown),
+        ([(invite, _)], []) => {
+            // Consume a single use, leaving the rest for concurrent joins
+            let uses = invite.uses + 1;
+            store.record_uses(&invite.code, uses)?;
+            Ok(Attribution::Invite { invite: StoredInvite { uses, ..invite.clone() }, confidence: Confidence::Certain })
+        }
+        ([], [invite]) => Ok(Attribution::Invite {
+            invite: StoredInvite { uses: invite.max_uses, ..invite.clone() },
+            confidence: Confidence::UsedUp,
+        }),
+        _ => {
+            for (invite, gained) in &used {
+                store.record_uses(&invite.code, invite.uses + gained)?;
+            }
+            let candidates = used.iter().map(|(inv, _)| inv.code.clone())
+                .chain(used_up.iter().map(|inv| inv.code.clone()))
+                .collect();
+            Ok(Attribution::Ambiguous { candidates })
+        }
+    }
+}
+
+/// Attribute every join in `pending`, oldest first, against the same `live`
+/// snapshot, emptying the queue. Each join consumes its use in turn, as if
+/// they had been handled one by one. Once the uses cannot be told apart, the
+/// joins left over share the same candidates.
+pub fn attribute_all<T>(store: &dyn MappingStore, guild_id: u64, pending: &mut Vec<T>, live: &[LiveInvite]) -> Vec<(T, StoreResult<Attribution>)> {
+    let mut ambiguous: Option<Vec<String>> = None;
+    pending.drain(..)
+        .map(|join| {
+            let attribution = match (attribute_join(store, guild_id, live), &ambiguous) {
+                (Ok(Attribution::Unknown), Some(candidates)) => Ok(Attribution::Ambiguous { candidates: candidates.clone() }),
+                (Ok(Attribution::Ambiguous { candidates }), _) => {
+                    ambiguous = Some(candidates.clone());
+                    Ok(Attribution::Ambiguous { candidates })
+                }
+                (attribution, _) => attribution,
+            };
+            (join, attribution)
+        })
+        .collect()
+}
+
+/// Attribute the joins in `missed`, which happened while the bot was not
+/// watching, against the `live` invites. Unlike joins queued while running,
+/// nothing tells which use came first, nor whether some members joined
+/// without an invite. So only when a single invite gained uses, at least as
+/// many as there are missed joins, are they all attributed to it; otherwise
+/// they are all `Ambiguous` between the invites that may have been used.
+/// Stored invites that are gone are only candidates if they may have been
+/// used up; others are taken to have expired. Use counts are left for
+/// `store::reconcile` to refresh.
+pub fn attribute_missed<T>(store: &dyn MappingStore, guild_id: u64, missed: Vec<T>, live: &[LiveInvite]) -> StoreResult<Vec<(T, Attribution)>> {
+    let stored = store.list_guild(guild_id)?;
+
+    // Invites that gained uses, including those created while we were away
+    let mut gained = Vec::<(&LiveInvite, u64)>::new();
+    for inv in live {
+        let before = stored.iter().find(|s| s.code == inv.code).map_or(0, |s| s.uses);
+        if inv.uses > before {
+            gained.push((inv, inv.uses - before));
+        }
+    }
+    // An invite that had more uses left than there are missed joins cannot
+    // have been used up by them
+    let used_up = stored.iter()
+        .filter(|s| s.max_uses > 0 && s.max_uses.saturating_sub(s.uses) <= missed.len() as u64)
+        .filter(|s| !live.iter().any(|inv| inv.code == s.code))
+        .map(|s| s.code.clone())
+        .collect::<Vec<_>>();
+
+    let attribution = match (gained.as_slice(), used_up.as_slice()) {
+        ([(inv, uses)], []) if *uses >= missed.len() as u64 => {
+            store.upsert_invite(guild_id, inv)?;
+            match store.get(guild_id, &inv.code)? {
+                Some(invite) => Attribution::Invite { invite, confidence: Confidence::Certain },
+                None => Attribution::Unknown,
+            }
+        }
+        ([], []) => Attribution::Unknown,
+        _ => Attribution::Ambiguous {
+            candidates: gained.iter().map(|(inv, _)| inv.code.clone()).chain(used_up).collect(),
+        },
+    };
+    Ok(missed.into_iter().map(|join| (join, attribution.clone())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    const GUILD: u64 = 1;
+
+    fn live(code: &str, uses: u64, max_uses: u64) -> LiveInvite {
+        LiveInvite { code: code.to_string(), uses, max_uses, inviter: None, created_at: 0 }
+    }
+
+    /// A store tracking `invites` as they were last seen, with role 10 linked
+    /// to each.
+    fn store(invites: &[LiveInvite]) -> MemoryStore {
+        let store = MemoryStore::new();
+        for inv in invites {
+            store.upsert_invite(GUILD, inv).unwrap();
+            store.link(GUILD, &inv.code, &[10]).unwrap();
+        }
+        store
+    }
+
+    fn uses(store: &MemoryStore, code: &str) -> Option<u64> {
+        store.get(GUILD, code).unwrap().map(|inv| inv.uses)
+    }
+
+    /// The code, use count and confidence of an attribution to an invite.
+    fn invite(attribution: &StoreResult<Attribution>) -> Option<(&str, u64, Confidence)> {
+        match attribution {
+            Ok(Attribution::Invite { invite, confidence }) => Some((invite.code.as_str(), invite.uses, *confidence)),
+            _ => None,
+        }
+    }
+
+    fn candidates(codes: &[&str]) -> Attribution {
+        Attribution::Ambiguous { candidates: codes.iter().map(|code| code.to_string()).collect() }
+    }
+
+    #[test]
+    fn concurrent_joins_on_one_invite_each_consume_a_use() {
+        let store = store(&[live("abc", 0, 0), live("def", 3, 0)]);
+        let now = [live("abc", 2, 0), live("def", 3, 0)];
+
+        let first = attribute_join(&store, GUILD, &now);
+        assert_eq!(invite(&first), Some(("abc", 1, Confidence::Certain)));
+        assert_eq!(uses(&store, "abc"), Some(1));
+        let second = attribute_join(&store, GUILD, &now);
+        assert_eq!(invite(&second), Some(("abc", 2, Confidence::Certain)));
+        assert_eq!(attribute_join(&store, GUILD, &now).unwrap(), Attribution::Unknown);
+    }
+
+    #[test]
+    fn queued_joins_on_one_invite_each_consume_a_use() {
+        let store = store(&[live("abc", 0, 0)]);
+        let mut pending = vec!["first", "second"];
+
+        let attributed = attribute_all(&store, GUILD, &mut pending, &[live("abc", 2, 0)]);
+        assert!(pending.is_empty());
+        let attributed = attributed.iter().map(|(join, a)| (*join, invite(a))).collect::<Vec<_>>();
+        assert_eq!(attributed, vec![
+            ("first", Some(("abc", 1, Confidence::Certain))),
+            ("second", Some(("abc", 2, Confidence::Certain))),
+        ]);
+    }
+
+    #[test]
+    fn two_invites_used_at_once_are_ambiguous() {
+        let store = store(&[live("abc", 0, 0), live("def", 4, 0)]);
+
+        let attribution = attribute_join(&store, GUILD, &[live("abc", 1, 0), live("def", 6, 0)]).unwrap();
+        assert_eq!(attribution, candidates(&["abc", "def"]));
+        // Every use is recorded, so the next join does not see them again
+        assert_eq!((uses(&store, "abc"), uses(&store, "def")), (Some(1), Some(6)));
+    }
+
+    #[test]
+    fn vanished_invite_one_use_from_its_max_was_used_up() {
+        let store = store(&[live("abc", 4, 5), live("old", 1, 5), live("def", 2, 0)]);
+
+        let attribution = attribute_join(&store, GUILD, &[live("def", 2, 0)]);
+        assert_eq!(invite(&attribution), Some(("abc", 5, Confidence::UsedUp)));
+        // The linked roles are still there to be assigned
+        assert!(matches!(attribution, Ok(Attribution::Invite { invite, .. }) if invite.roles == [10]));
+        // Both are forgotten, though the one far from its max just expired
+        assert_eq!((uses(&store, "abc"), uses(&store, "old")), (None, None));
+    }
+
+    #[test]
+    fn used_up_invite_and_another_used_are_ambiguous() {
+        let store = store(&[live("abc", 4, 5), live("def", 2, 0)]);
+
+        let attribution = attribute_join(&store, GUILD, &[live("def", 3, 0)]).unwrap();
+        assert_eq!(attribution, candidates(&["def", "abc"]));
+    }
+
+    #[test]
+    fn invite_created_unnoticed_is_tracked_and_attributed() {
+        let store = store(&[live("abc", 1, 0)]);
+
+        let attribution = attribute_join(&store, GUILD, &[live("abc", 1, 0), live("new", 1, 0)]);
+        assert_eq!(invite(&attribution), Some(("new", 1, Confidence::Certain)));
+        assert_eq!(uses(&store, "new"), Some(1));
+        assert!(store.get(GUILD, "new").unwrap().unwrap().roles.is_empty());
+    }
+
+    #[test]
+    fn unused_invite_created_unnoticed_is_only_tracked() {
+        let store = store(&[]);
+
+        assert_eq!(attribute_join(&store, GUILD, &[live("new", 0, 0)]).unwrap(), Attribution::Unknown);
+        assert_eq!(uses(&store, "new"), Some(0));
+    }
+
+    #[test]
+    fn ambiguity_carries_over_to_later_queued_joins() {
+        let store = store(&[live("abc", 0, 0), live("def", 0, 0)]);
+        let mut pending = vec!["first", "second", "third"];
+
+        let attributed = attribute_all(&store, GUILD, &mut pending, &[live("abc", 1, 0), live("def", 1, 0)]);
+        for (join, attribution) in attributed {
+            assert_eq!(attribution.unwrap(), candidates(&["abc", "def"]), "{}", join);
+        }
+    }
+
+    #[test]
+    fn missed_joins_on_one_invite_are_attributed_to_it() {
+        let store = store(&[live("abc", 0, 0), live("def", 1, 0)]);
+
+        let attributed = attribute_missed(&store, GUILD, vec!["first", "second"], &[live("abc", 2, 0), live("def", 1, 0)]).unwrap();
+        for (_, attribution) in attributed {
+            assert!(matches!(attribution, Attribution::Invite { invite, confidence: Confidence::Certain } if invite.code == "abc"));
+        }
+    }
+
+    #[test]
+    fn missed_joins_ignore_limited_invites_that_expired() {
+        // Five uses were left on the gone invite, more than two joins could use
+        let store = store(&[live("abc", 0, 0), live("expired", 5, 10)]);
+
+        let attributed = attribute_missed(&store, GUILD, vec!["first", "second"], &[live("abc", 2, 0)]).unwrap();
+        for (_, attribution) in attributed {
+            assert_eq!(attribution.confidence(), Confidence::Certain);
+        }
+    }
+
+    #[test]
+    fn missed_joins_may_have_used_up_a_gone_invite() {
+        let store = store(&[live("abc", 0, 0), live("full", 8, 10)]);
+
+        let attributed = attribute_missed(&store, GUILD, vec!["first", "second"], &[live("abc", 2, 0)]).unwrap();
+        for (_, attribution) in attributed {
+            assert_eq!(attribution, candidates(&["abc", "full"]));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+/* Command line flags and exit codes. The bot runs unattended (under systemd or
+ * in a container), so startup never waits for input: anything it cannot sort
+ * out by itself is a fatal error with one of the exit codes below. */
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+use std::process;
+
+// Exit codes, following sysexits.h so service managers can tell them apart
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_NO_STORE: i32 = 66;
+pub const EXIT_UNAVAILABLE: i32 = 69;
+pub const EXIT_IO_ERROR: i32 = 74;
+pub const EXIT_TEMPFAIL: i32 = 75;
+pub const EXIT_CONFIG: i32 = 78;
+
+pub const USAGE: &str = "Usage: tcysm-bot [--config <path>] [--init | --create-db | --restore-backup]
+
+  --config <path>   Read the configuration from <path> instead of config.toml
+  --init            Create the invite store (importing the legacy JSON file if set) and exit
+  --create-db       Create the invite store if it does not exist, then start the bot
+  --restore-backup  Replace the invite store with its newest intact backup and exit
+  -h, --help        Show this message";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// The config file to read instead of `config::DEFAULT_PATH`.
+    pub config: Option<PathBuf>,
+    /// Only set up the invite store, do not connect to Discord.
+    pub init: bool,
+    /// Create the invite store if it is missing.
+    pub create_db: bool,
+    /// Put the newest intact backup of the store in its place, then exit.
+    pub restore_backup: bool,
+    pub help: bool,
+}
+
+/// Flags that make no sense, reported along with `USAGE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageError(pub String);
+
+impl UsageError {
+    pub fn exit_code(&self) -> i32 {
+        EXIT_USAGE
+    }
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n\n{}", self.0, USAGE)
+    }
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, UsageError> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => options.config = Some(args.next().ok_or_else(|| UsageError("--config needs a path".to_string()))?.into()),
+                "--init" => options.init = true,
+                "--create-db" => options.create_db = true,
+                "--restore-backup" => options.restore_backup = true,
+                "-h" | "--help" => options.help = true,
+                _ => return Err(UsageError(format!("Unknown argument {}", arg))),
+            }
+        }
+        if options.restore_backup && options.may_create_store() {
+            return Err(UsageError("--restore-backup cannot be combined with --init or --create-db".to_string()));
+        }
+        Ok(options)
+    }
+
+    /// Whether the bot connects to Discord at all.
+    pub fn connects(&self) -> bool {
+        !(self.init || self.restore_backup)
+    }
+
+    /// Whether a missing invite store may be created.
+    pub fn may_create_store(&self) -> bool {
+        self.init || self.create_db
+    }
+}
+
+/// Print `message` and exit with `code`.
+pub fn fatal(code: i32, message: impl Display) -> ! {
+    eprintln!("{}", message);
+    process::exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, UsageError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_are_read() {
+        assert_eq!(parse(&[]).unwrap(), Options::default());
+        let options = parse(&["--config", "/etc/tcysm.toml", "--create-db"]).unwrap();
+        assert_eq!(options.config, Some(PathBuf::from("/etc/tcysm.toml")));
+        assert!(options.create_db && options.connects() && options.may_create_store());
+        assert!(!parse(&["--init"]).unwrap().connects());
+        assert!(parse(&["-h"]).unwrap().help);
+    }
+
+    #[test]
+    fn restoring_is_a_mode_of_its_own() {
+        assert!(!parse(&["--restore-backup"]).unwrap().connects());
+        for other in ["--init", "--create-db"] {
+            let why = parse(&["--restore-backup", other]).unwrap_err();
+            assert_eq!(why.0, "--restore-backup cannot be combined with --init or --create-db");
+            assert_eq!(why.exit_code(), EXIT_USAGE);
+        }
+    }
+
+    #[test]
+    fn unknown_arguments_are_refused() {
+        let why = parse(&["--init", "--verbose"]).unwrap_err();
+        assert_eq!(why.0, "Unknown argument --verbose");
+        assert_eq!(why.exit_code(), EXIT_USAGE);
+        assert!(why.to_string().ends_with(USAGE));
+    }
+
+    #[test]
+    fn flags_need_their_values() {
+        let why = parse(&["--config"]).unwrap_err();
+        assert_eq!(why.0, "--config needs a path");
+        assert_eq!(why.exit_code(), EXIT_USAGE);
+    }
+}
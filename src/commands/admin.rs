@@ -0,0 +1,236 @@
+/* Commands for the bot's owners, to look after the bot itself rather than a
+ * guild: `!admin status|guilds|reconcile|reload|shutdown`. */
+use std::sync::Arc;
+use std::time::Duration;
+
+use serenity::framework::standard::macros::command;
+use serenity::framework::standard::{Args, CommandResult};
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use serenity::utils::MessageBuilder;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::{BotConfig, BotHealth, InviteTracker, ShardManagerContainer};
+
+/// A duration in the largest units that matter, e.g. `3d 4h 12m`.
+fn format_uptime(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m {}s", minutes, secs % 60),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+// !admin status
+// How long the bot has been up, its connection, the guilds and cache, and the
+// invite store.
+#[command]
+#[description = "Show the bot's uptime, connection, cache and invite store"]
+async fn status(ctx: &Context, msg: &Message) -> CommandResult {
+    let (store, config, health) = {
+        let data = ctx.data.read().await;
+        (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+         data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone(),
+         data.get::<BotHealth>().expect("Expected BotHealth in data/typemap").clone())
+    };
+
+    let invites = store.list().map(|invites| invites.len());
+    let report = health.report(invites.is_ok());
+    let guilds = ctx.cache.guilds();
+    let served = guilds.iter().filter(|id| config.serves(id.0)).count();
+
+    let mut response = MessageBuilder::new();
+    response.push_line(format!("Up for {}", format_uptime(health.uptime())));
+    response.push(format!("Gateway: {} for {}", report.gateway, format_uptime(Duration::from_secs(report.gateway_since))));
+    match report.last_event {
+        Some(at) => response.push_line(format!(", last event <t:{}:R>", at)),
+        None => response.push_line(""),
+    };
+    response.push_line(format!("Invites reconciled since connecting: {}", if report.reconciled { "yes" } else { "no" }));
+    response.push_line(format!("Guilds: {}, of which {} served", guilds.len(), served));
+    response.push_line(format!(
+        "Cache: {} users, {} channels, {} unavailable guilds",
+        ctx.cache.user_count(), ctx.cache.guild_channel_count(), ctx.cache.unavailable_guilds().len()
+    ));
+    response.push("Invite store: ").push_mono_safe(config.storage.path.display().to_string());
+    match invites {
+        Ok(count) => response.push_line(format!(", {} invites tracked", count)),
+        Err(why) => {
+            error!(error = %why, "Error reading the invite store");
+            response.push_line(", could not be read")
+        }
+    };
+
+    if let Err(why) = msg.channel_id.say(&ctx, &response).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
+
+// !admin guilds
+// Every guild the bot is in, and whether it is configured to serve it.
+#[command]
+#[description = "List the guilds the bot is in"]
+async fn guilds(ctx: &Context, msg: &Message) -> CommandResult {
+    let (store, config) = {
+        let data = ctx.data.read().await;
+        (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+         data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone())
+    };
+
+    let mut response = MessageBuilder::new();
+    let guilds = ctx.cache.guilds();
+    if guilds.is_empty() {
+        response.push_italic_line("Not in any guild");
+    }
+    for guild_id in guilds {
+        match ctx.cache.guild_field(guild_id, |g| (g.name.clone(), g.member_count)) {
+            Some((name, members)) => response.push_bold_safe(name).push(format!(" ({}): {} members", guild_id, members)),
+            None => response.push(format!("{}: unavailable", guild_id)),
+        };
+        match store.list_guild(guild_id.0) {
+            Ok(invites) => response.push(format!(", {} invites tracked", invites.len())),
+            Err(why) => {
+                error!(guild = %guild_id, error = %why, "Error listing invites");
+                response.push(", invites could not be read")
+            }
+        };
+        if !config.serves(guild_id.0) {
+            response.push_italic(" (not served)");
+        }
+        response.push_line("");
+    }
+
+    if let Err(why) = msg.channel_id.say(&ctx, &response).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
+
+// !admin reconcile [guild-id]
+// Reconciles the invites of one guild, or of every guild the bot serves, right
+// away rather than waiting for `reconcile_minutes`.
+#[command]
+#[description = "Reconcile the invites of every served guild, or of one"]
+#[usage = "[guild-id]"]
+#[max_args(1)]
+async fn reconcile(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let config = {
+        let data = ctx.data.read().await;
+        data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone()
+    };
+
+    let guild_ids = if args.is_empty() {
+        ctx.cache.guilds().into_iter().filter(|id| config.serves(id.0)).collect()
+    } else {
+        match args.single::<u64>() {
+            Ok(id) if config.serves(id) && ctx.cache.guilds().contains(&GuildId(id)) => vec![GuildId(id)],
+            _ => {
+                if let Err(why) = msg.channel_id.say(&ctx, format!("Not in a served guild with that ID. See {}admin guilds.", config.prefix)).await {
+                    warn!(error = ?why, "Error sending message");
+                }
+                return Ok(());
+            }
+        }
+    };
+
+    let (mut added, mut removed, mut recounted) = (0, 0, 0);
+    let mut failed = Vec::new();
+    for guild_id in &guild_ids {
+        match crate::reconcile_guild(ctx, *guild_id, false).await {
+            Ok(drift) => {
+                added += drift.added.len();
+                removed += drift.removed.len();
+                recounted += drift.recounted.len();
+            }
+            Err(why) => {
+                error!(guild = %guild_id, error = %why, "Could not reconcile invites");
+                failed.push(guild_id.to_string());
+            }
+        }
+    }
+
+    let mut reply = format!(
+        "Reconciled {} guilds: tracked {} new invites, removed {} that no longer exist and corrected {} use counts.",
+        guild_ids.len() - failed.len(), added, removed, recounted
+    );
+    if !failed.is_empty() {
+        reply.push_str(&format!("\nFailed to reconcile {}, see the logs.", failed.join(", ")));
+    }
+    if let Err(why) = msg.channel_id.say(&ctx, reply).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
+
+// !admin reload
+// Re-reads the config file and the invite store. The guilds served, the
+// moderator roles and the deny-list change right away, anything else needs a
+// restart.
+#[command]
+#[description = "Reload the configuration and the invite store"]
+async fn reload(ctx: &Context, msg: &Message) -> CommandResult {
+    let (store, current) = {
+        let data = ctx.data.read().await;
+        (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+         data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone())
+    };
+
+    let config = match Config::load(current.source.as_deref(), true) {
+        Ok(config) => config,
+        Err(why) => {
+            warn!(error = %why, "Could not reload the configuration");
+            if let Err(why) = msg.channel_id.say(&ctx, format!("{}\nKept the current configuration.", why)).await {
+                warn!(error = ?why, "Error sending message");
+            }
+            return Ok(());
+        }
+    };
+    if let Err(why) = store.reload() {
+        error!(error = %why, "Could not reload the invite store");
+        if let Err(why) = msg.channel_id.say(&ctx, format!("Could not reload the invite store: {}\nKept the current configuration.", why)).await {
+            warn!(error = ?why, "Error sending message");
+        }
+        return Ok(());
+    }
+
+    let restart = current.restart_needed(&config);
+    {
+        let mut data = ctx.data.write().await;
+        data.insert::<BotConfig>(Arc::new(config));
+    }
+    info!(restart_needed = ?restart, "Reloaded the configuration and the invite store");
+
+    let mut reply = "Reloaded the configuration and the invite store.".to_string();
+    if !restart.is_empty() {
+        reply.push_str(&format!("\nChanges to {} take effect after a restart.", restart.join(", ")));
+    }
+    if let Err(why) = msg.channel_id.say(&ctx, reply).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
+
+// !admin shutdown
+// Shuts the bot down the same way SIGTERM does: no new commands, disconnect,
+// then wait for joins being attributed before exiting.
+#[command]
+#[description = "Shut the bot down cleanly"]
+async fn shutdown(ctx: &Context, msg: &Message) -> CommandResult {
+    let (health, shard_manager) = {
+        let data = ctx.data.read().await;
+        (data.get::<BotHealth>().expect("Expected BotHealth in data/typemap").clone(),
+         data.get::<ShardManagerContainer>().expect("Expected ShardManagerContainer in data/typemap").clone())
+    };
+
+    info!(user = %msg.author.id, "Shutting down on request");
+    if let Err(why) = msg.channel_id.say(&ctx, "Shutting down.").await {
+        warn!(error = ?why, "Error sending message");
+    }
+    health.set_shutting_down();
+    shard_manager.lock().await.shutdown_all().await;
+    Ok(())
+}
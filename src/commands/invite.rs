@@ -1,13 +1,16 @@
-use std::{env, fs};
+use std::collections::HashMap;
 
 use serenity::{framework::standard::macros::command, utils::MessageBuilder};
 use serenity::framework::standard::{CommandResult, Args};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
+use tracing::{debug, error, warn};
 
-use serde::{Deserialize, Serialize};
-
-use crate::{InviteTracker, InviteRoles};
+use super::prefix;
+use crate::{BotConfig, InviteTracker};
+use crate::roles::{self, BotRank, RoleError, RoleInfo, Ungrantable};
+use crate::stats::{self, Summary};
+use crate::store::{self, Confidence, JoinFilter, LiveInvite, MappingStore};
 
 /* The aim here is to...:
  * 1. Create an invite with `inv new ...`
@@ -16,157 +19,1069 @@ use crate::{InviteTracker, InviteRoles};
  * 4. Profit
  */
 
-#[derive(Deserialize, Serialize)]
-struct Invite {
-    invite_id: String,
-    roles: Vec<Role>,
-    uses: u32,
+/// Look up linked role IDs among the guild's current roles. Returns the roles
+/// that still exist, and the IDs of the ones deleted since they were linked.
+pub fn resolve_roles<'a>(guild_roles: &'a HashMap<RoleId, Role>, ids: &[u64]) -> (Vec<&'a Role>, Vec<u64>) {
+    let mut found = Vec::new();
+    let mut deleted = Vec::new();
+    for id in ids {
+        match guild_roles.get(&RoleId(*id)) {
+            Some(role) => found.push(role),
+            None => deleted.push(*id),
+        }
+    }
+    (found, deleted)
+}
+
+/// The parts of the guild's invites that the store and attribution care about.
+pub fn live_invites(invites: &[RichInvite]) -> Vec<LiveInvite> {
+    invites.iter()
+        .map(|inv| LiveInvite {
+            code: inv.code.clone(),
+            uses: inv.uses,
+            max_uses: inv.max_uses,
+            inviter: inv.inviter.as_ref().map(|u| u.id.0),
+            created_at: inv.created_at.unix_timestamp(),
+        })
+        .collect()
+}
+
+/// Resolve a role argument to one of the guild's roles, given as a mention,
+/// an ID or its name. See `roles::resolve`.
+pub fn parse_role(guild_roles: &HashMap<RoleId, Role>, arg: &str) -> Result<u64, RoleError> {
+    let roles = guild_roles.values().map(|r| (r.id.0, r.name.as_str())).collect::<Vec<(u64, &str)>>();
+    roles::resolve(&roles, arg)
+}
+
+/// What the bot may do with roles in the guild: its highest role and its
+/// permissions.
+async fn bot_rank(ctx: &Context, guild: &Guild) -> Option<BotRank> {
+    let bot_id = ctx.cache.current_user_id();
+    let member = match guild.members.get(&bot_id) {
+        Some(member) => member.clone(),
+        None => match guild.id.member(ctx, bot_id).await {
+            Ok(member) => member,
+            Err(why) => {
+                warn!(error = ?why, "Could not get the bot's own member");
+                return None;
+            }
+        },
+    };
+    let top_position = member.roles.iter().filter_map(|id| guild.roles.get(id)).map(|role| role.position).max().unwrap_or(0);
+    Some(BotRank { top_position, permissions: guild.member_permissions(&member) })
+}
+
+/// Check whether the bot can and may give each of `roles` to members, see
+/// `roles::check_grantable`. Returns each role that exists with its name and
+/// the outcome, or `None` if the guild could not be looked up.
+pub async fn check_roles(ctx: &Context, guild_id: GuildId, roles: &[u64]) -> Option<Vec<(u64, String, Result<(), Ungrantable>)>> {
+    let deny = {
+        let data = ctx.data.read().await;
+        data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").deny.clone()
+    };
+    let guild = ctx.cache.guild(guild_id)?;
+    let rank = bot_rank(ctx, &guild).await?;
+
+    let checked = roles.iter()
+        .filter_map(|id| guild.roles.get(&RoleId(*id)))
+        .map(|role| {
+            let info = RoleInfo {
+                id: role.id.0,
+                name: &role.name,
+                position: role.position,
+                managed: role.managed,
+                permissions: role.permissions,
+            };
+            (role.id.0, role.name.clone(), roles::check_grantable(&info, guild_id.0, &rank, &deny))
+        })
+        .collect();
+    Some(checked)
+}
+
+/// Split `roles` into those that can be linked to an invite, and the reasons
+/// the others cannot. For `link` and `create`.
+pub async fn grantable_roles(ctx: &Context, guild_id: GuildId, roles: &[u64]) -> (Vec<u64>, Vec<String>) {
+    let checked = match check_roles(ctx, guild_id, roles).await {
+        Some(checked) => checked,
+        None => return (Vec::new(), vec!["Could not check whether the bot can give these roles, try again in a moment.".to_string()]),
+    };
+    let mut grantable = Vec::new();
+    let mut refused = Vec::new();
+    for (id, name, checked) in checked {
+        match checked {
+            Ok(()) => grantable.push(id),
+            Err(why) => refused.push(format!("Cannot link {}: {}.", name, why)),
+        }
+    }
+    (grantable, refused)
+}
+
+/// Role mentions, so Discord renders each role with its current name and
+/// colour. Send these with mentions disabled so nobody gets pinged.
+fn describe_roles(guild_roles: &HashMap<RoleId, Role>, ids: &[u64]) -> String {
+    let (found, deleted) = resolve_roles(guild_roles, ids);
+    found.iter()
+        .map(|r| r.mention().to_string())
+        .chain(deleted.iter().map(|id| format!("*deleted role {}*", id)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Discord's limits for invites
+pub const MAX_INVITE_AGE: u64 = 7 * 24 * 60 * 60;
+pub const MAX_INVITE_USES: u64 = 100;
+
+/// Parse a duration like `30m`, `12h`, `1d` or `1w` into seconds. A bare
+/// number is taken as seconds.
+fn parse_duration(s: &str) -> Option<u64> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// The inverse of `parse_duration`, in the largest unit that fits exactly.
+fn format_duration(secs: u64) -> String {
+    for (unit, name) in [(7 * 24 * 60 * 60, "w"), (24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")] {
+        if secs >= unit && secs.is_multiple_of(unit) {
+            return format!("{}{}", secs / unit, name);
+        }
+    }
+    format!("{}s", secs)
+}
+
+/// The arguments of `!invite create`.
+struct CreateOptions {
+    channel: ChannelId,
+    max_age: u64,
+    max_uses: u64,
+    roles: Vec<String>,
+    label: Option<String>,
+}
+
+impl CreateOptions {
+    fn parse(tokens: &[String]) -> Result<Self, String> {
+        let mut channel = None;
+        let mut max_age = 0;
+        let mut max_uses = 0;
+        let mut roles = Vec::new();
+        let mut label = None;
+
+        let mut tokens = tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            match token.as_str() {
+                "--age" => {
+                    let value = tokens.next().ok_or("--age needs a value, e.g. 1d")?;
+                    max_age = parse_duration(value)
+                        .filter(|age| *age <= MAX_INVITE_AGE)
+                        .ok_or_else(|| format!("Invalid age {}, use e.g. 30m, 12h or 7d (at most 7 days)", value))?;
+                }
+                "--uses" => {
+                    let value = tokens.next().ok_or("--uses needs a value, e.g. 50")?;
+                    max_uses = value.parse::<u64>().ok()
+                        .filter(|uses| *uses <= MAX_INVITE_USES)
+                        .ok_or_else(|| format!("Invalid number of uses {}, at most {} are allowed", value, MAX_INVITE_USES))?;
+                }
+                "--roles" => {
+                    // Every argument up to the next option is a role
+                    while let Some(role) = tokens.next_if(|t| !t.starts_with("--")) {
+                        roles.push(role.clone());
+                    }
+                    if roles.is_empty() {
+                        return Err("--roles needs at least one role".to_string());
+                    }
+                }
+                "--label" => {
+                    label = Some(tokens.next().ok_or("--label needs a value, e.g. \"Career fair\"")?.clone());
+                }
+                option if option.starts_with("--") => return Err(format!("Unknown option {}", option)),
+                _ if channel.is_none() => {
+                    channel = Some(token.parse::<ChannelId>().map_err(|_| format!("No channel {} found", token))?);
+                }
+                _ => return Err(format!("Unexpected argument {}", token)),
+            }
+        }
+
+        Ok(CreateOptions {
+            channel: channel.ok_or("A channel is required")?,
+            max_age,
+            max_uses,
+            roles,
+            label,
+        })
+    }
+}
+
+/// The guild's roles as the cache knows them, for describing linked roles.
+pub fn guild_roles(ctx: &Context, guild_id: GuildId) -> HashMap<RoleId, Role> {
+    ctx.cache.guild_field(guild_id, |g| g.roles.clone()).unwrap_or_default()
+}
+
+/// An invite to create, with the roles to link to it already resolved.
+pub struct NewInvite {
+    pub channel: ChannelId,
+    /// Seconds until the invite expires, 0 for never.
+    pub max_age: u64,
+    /// How many uses the invite allows, 0 for unlimited.
+    pub max_uses: u64,
+    pub roles: Vec<u64>,
+    pub label: Option<String>,
+}
+
+/// Create an invite and store it with its roles and label in one go, for
+/// `!invite create` and `/invite create`. Returns the reply.
+pub async fn create_invite(ctx: &Context, store: &dyn MappingStore, guild_id: GuildId, guild_roles: &HashMap<RoleId, Role>, inviter: UserId, new: NewInvite) -> String {
+    let invite = match new.channel.create_invite(ctx, |i| i.max_age(new.max_age).max_uses(new.max_uses).unique(true)).await {
+        Ok(invite) => invite,
+        Err(why) => {
+            error!(channel = %new.channel, error = ?why, "Error creating invite");
+            return format!("Error creating invite for channel {}", new.channel.mention());
+        }
+    };
+
+    // Store the invite with its roles and label in one go. If that fails,
+    // delete the invite again rather than leave it without its roles.
+    let stored = store::StoredInvite {
+        code: invite.code.clone(),
+        guild_id: guild_id.0,
+        roles: new.roles,
+        uses: 0,
+        max_uses: new.max_uses,
+        inviter: Some(inviter.0),
+        label: new.label,
+        created_at: Some(invite.created_at.unix_timestamp()),
+        note: None,
+    };
+    if let Err(why) = store.save_invite(&stored) {
+        error!(invite = %invite.code, error = %why, "Error saving invite");
+        if let Err(why) = invite.delete(ctx).await {
+            error!(invite = %invite.code, error = ?why, "Error deleting invite");
+        }
+        return "Failed to save the invite, so it was deleted again.".to_string();
+    }
+
+    let mut response = MessageBuilder::new();
+    response.push_line(format!("Created https://discord.gg/{} for {}", stored.code, new.channel.mention()));
+    if let Some(label) = &stored.label {
+        response.push("Label: ").push_line_safe(label.as_str());
+    }
+    match new.max_age {
+        0 => response.push_line("Expires: never"),
+        age => response.push_line(format!("Expires: after {}", format_duration(age))),
+    };
+    match new.max_uses {
+        0 => response.push_line("Uses: unlimited"),
+        uses => response.push_line(format!("Uses: {}", uses)),
+    };
+    if stored.roles.is_empty() {
+        response.push_line("Roles: none");
+    } else {
+        response.push_line(format!("Roles: {}", describe_roles(guild_roles, &stored.roles)));
+    }
+    response.build()
 }
 
+// !invite create <#channel> [--age 1d] [--uses 50] [--roles "Role A" "Role B"] [--label "Career fair"]
+// Creates an invite with the given roles linked to it in one go. Without
+// --age or --uses the invite never expires.
 #[command]
+#[description = "Create an invite with roles linked to it in one go"]
+#[usage = "<#channel> [--age 1d] [--uses 50] [--roles \"Role A\" \"Role B\"] [--label \"Career fair\"]"]
+#[example = "#welcome --age 7d --uses 100 --roles Alumni --label \"Career fair\""]
+#[min_args(1)]
+#[bucket = "discord"]
 async fn create(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    // !inv create exp_age exp_uses channel
-    let maxage = args.single::<u64>().unwrap_or(0);
-    let maxuses = args.single::<u64>().unwrap_or(0);
-    let chan = args.single::<ChannelId>().unwrap_or(ChannelId(0));
-    if let Ok(invite) = chan.create_invite(ctx, |i| i.max_age(maxage).max_uses(maxuses).unique(true)).await {
-        if let Err(why) = msg.channel_id.say(&ctx, format!("Created invite {code} for {chan} with a max age of {maxage} seconds, and max uses {maxuses}", code=invite.code)).await {
-            println!("Error sending message to channel: {:?}", why);
+    let store = {
+        let data = ctx.data.read().await;
+        data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+    };
+
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
         }
-    } else {
-        if let Err(why) = msg.channel_id.say(&ctx, format!("Error creating invite for channel {chan}")).await {
-            println!("Error sending message to channel {chan}: {:?}", why);
+    };
+
+    let tokens = args.iter::<String>().quoted().filter_map(Result::ok).collect::<Vec<String>>();
+    let options = match CreateOptions::parse(&tokens) {
+        Ok(options) if guild.channels.contains_key(&options.channel) => options,
+        Ok(options) => {
+            if let Err(why) = msg.channel_id.say(&ctx, format!("Channel {} is not in this server.", options.channel.mention())).await {
+                warn!(error = ?why, "Error sending message");
+            }
+            return Ok(());
+        }
+        Err(why) => {
+            let usage = format!("{}invite create <#channel> [--age 1d] [--uses 50] [--roles \"Role A\" \"Role B\"] [--label \"Career fair\"]", prefix(ctx).await);
+            if let Err(why) = msg.channel_id.say(&ctx, format!("{}\nUsage: {}", why, usage)).await {
+                warn!(error = ?why, "Error sending message");
+            }
+            return Ok(());
+        }
+    };
+
+    // Resolve every role before creating anything, so a typo does not leave
+    // behind an invite without its roles
+    let mut roles = Vec::<u64>::new();
+    let mut response = MessageBuilder::new();
+    for arg in &options.roles {
+        match parse_role(&guild.roles, arg) {
+            Ok(id) => roles.push(id),
+            Err(why) => {
+                response.push_line_safe(why.to_string());
+            }
+        }
+    }
+    // Nor one linked to roles the bot cannot give
+    if roles.len() == options.roles.len() {
+        let (grantable, refused) = grantable_roles(ctx, guild.id, &roles).await;
+        for why in &refused {
+            response.push_line_safe(why.as_str());
+        }
+        roles = grantable;
+    }
+    if roles.len() < options.roles.len() {
+        response.push("No invite was created.");
+        if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+            warn!(error = ?why, "Error sending message");
         }
+        return Ok(());
     }
 
+    let new = NewInvite {
+        channel: options.channel,
+        max_age: options.max_age,
+        max_uses: options.max_uses,
+        roles,
+        label: options.label,
+    };
+    let response = create_invite(ctx, store.as_ref(), guild.id, &guild.roles, msg.author.id, new).await;
+    if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+        warn!(error = ?why, "Error sending message");
+    }
     Ok(())
 }
 
+/// Link roles to a tracked invite, for `!invite link` and `/invite link`.
+/// Returns the reply.
+pub fn link_roles(store: &dyn MappingStore, guild_id: GuildId, guild_roles: &HashMap<RoleId, Role>, invite: &str, roles: &[u64]) -> String {
+    match store.get(guild_id.0, invite) {
+        Ok(Some(_)) => {}
+        Ok(None) => return format!("Invite {} is not tracked.", invite),
+        Err(why) => {
+            error!(invite = %invite, error = %why, "Error reading invite");
+            return "Failed to read the invite store.".to_string();
+        }
+    }
+
+    match store.link(guild_id.0, invite, roles) {
+        Ok(added) if added.is_empty() => format!("{} were already linked to {}.", describe_roles(guild_roles, roles), invite),
+        Ok(added) => format!("Linked to {}: {}", invite, describe_roles(guild_roles, &added)),
+        Err(why) => {
+            error!(invite = %invite, error = %why, "Error linking roles");
+            "Failed to save the linked roles, nothing was changed.".to_string()
+        }
+    }
+}
+
 #[command]
-// #[allowed_roles("mod")] // Commented out for debugging purposes
+#[description = "Link roles to a tracked invite, to be given to members who join through it"]
+#[usage = "<invite-code> <roles...>"]
+#[example = "abc123 Alumni \"Career fair\""]
+#[min_args(2)]
 async fn link(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let data_locked = {
+    let store = {
         let data = ctx.data.read().await;
         data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
     };
 
-    println!("{:?}", args);
-
     // Check that we get the guild OK
-    if let Some(guild) = msg.guild(&ctx.cache) {
-        // Get one argument (the invite code) and advance the arg iterator
-        match args.single_quoted::<String>() {
-            Ok(invite) => {
-                {
-                    // Is the invite in the cache?
-                    let mut invites = data_locked.write().await;
-                    if let std::collections::hash_map::Entry::Occupied(mut entry) = invites.entry(invite.clone()) {
-                        // Get the roles (rest of the args) and add them to the cache
-                        if args.is_empty() {
-                            if let Err(why) = msg.channel_id.say(&ctx, "Role arguments required: !invite <invite-code> <[roles]>").await {
-                                println!("Failed to send message: {:?}", why);
-                            }
-                            println!("No role arguments given");
-                        }
-
-                        // We have at least one role specified
-                        for arg in args.iter().quoted() {
-                            let arg = arg.unwrap_or("".to_string());
-                            let arg_temp = arg.clone();
-                            if let Some(role) = guild.role_by_name(&arg) {
-                                println!("Adding role: {:?}", role);
-                                // Add the specified role to the hashmap. Does this even need extra scoping?
-                                entry.get_mut().0.push(role.to_owned());
-                            } else {
-                                if let Err(why) = msg.channel_id.say(&ctx, "No role ".to_string() + &arg_temp + " found.").await {
-                                    println!("Error sending message: {:?}", why);
-                                }
-                            }
-                        }
-                    }
-                }
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
+
+    // Get one argument (the invite code) and advance the arg iterator
+    let invite = match args.single_quoted::<String>() {
+        Ok(invite) if !args.is_empty() => invite,
+        _ => {
+            if let Err(why) = msg.channel_id.say(&ctx, format!("Role arguments required: {}invite link <invite-code> <[roles]>", prefix(ctx).await)).await {
+                warn!(error = ?why, "Error sending message");
             }
-            Err(_) => {
-                print!("An argument is required to run this command.");
+            debug!("No invite code or role arguments given");
+            return Ok(());
+        }
+    };
+
+    // The roles are the rest of the args
+    let mut roles = Vec::<u64>::new();
+    let mut response = MessageBuilder::new();
+    for arg in args.iter::<String>().quoted() {
+        let arg = arg.unwrap_or_default();
+        match parse_role(&guild.roles, &arg) {
+            Ok(id) => {
+                debug!(role = id, "Adding role");
+                roles.push(id);
+            }
+            Err(why) => {
+                response.push_line_safe(why.to_string());
             }
         }
-    } else {
-        println!("No guild found");
     }
-    // React or show error
+
+    // Only link roles the bot can actually give, then persist them in one go
+    let (roles, refused) = grantable_roles(ctx, guild.id, &roles).await;
+    for why in &refused {
+        response.push_line_safe(why.as_str());
+    }
+    if !roles.is_empty() {
+        response.push(link_roles(store.as_ref(), guild.id, &guild.roles, &invite, &roles));
+    }
+    if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+        warn!(error = ?why, "Error sending message");
+    }
     Ok(())
 }
 
+/// Unlink roles from a tracked invite, or all of its roles if `roles` is
+/// `None`, for `!invite unlink` and `/invite unlink`. Returns the reply.
+pub fn unlink_roles(store: &dyn MappingStore, guild_id: GuildId, guild_roles: &HashMap<RoleId, Role>, invite: &str, roles: Option<&[u64]>) -> String {
+    match store.get(guild_id.0, invite) {
+        Ok(Some(_)) => {}
+        Ok(None) => return format!("Invite {} is not tracked.", invite),
+        Err(why) => {
+            error!(invite = %invite, error = %why, "Error reading invite");
+            return "Failed to read the invite store.".to_string();
+        }
+    }
+
+    let removed = match store.unlink(invite, roles) {
+        Ok(removed) => removed,
+        Err(why) => {
+            error!(invite = %invite, error = %why, "Error unlinking roles");
+            return "Failed to save the unlinked roles, nothing was changed.".to_string();
+        }
+    };
+    let remaining = match store.get(guild_id.0, invite) {
+        Ok(Some(inv)) => inv.roles,
+        _ => Vec::new(),
+    };
+
+    let mut response = MessageBuilder::new();
+    if removed.is_empty() {
+        response.push_line(format!("No matching roles were linked to {}.", invite));
+    } else {
+        response.push_line(format!("Unlinked from {}: {}", invite, describe_roles(guild_roles, &removed)));
+    }
+    if remaining.is_empty() {
+        response.push_italic_line("No roles linked");
+    } else {
+        response.push_line(format!("Still linked: {}", describe_roles(guild_roles, &remaining)));
+    }
+    response.build()
+}
+
+// !invite unlink <invite-code> [roles]
+// Removes the given roles from the invite, or all of its roles if none are given.
 #[command]
-async fn unlink(_ctx: &Context, _msg: &Message) -> CommandResult {
-    unimplemented!();
+#[description = "Unlink roles from an invite, or all of its roles if none are given"]
+#[usage = "<invite-code> [roles...]"]
+#[example = "abc123 Alumni"]
+#[min_args(1)]
+async fn unlink(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = {
+        let data = ctx.data.read().await;
+        data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+    };
+
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
+
+    let invite = match args.single_quoted::<String>() {
+        Ok(invite) => invite,
+        Err(_) => {
+            if let Err(why) = msg.channel_id.say(&ctx, format!("Invite code required: {}invite unlink <invite-code> [roles]", prefix(ctx).await)).await {
+                warn!(error = ?why, "Error sending message");
+            }
+            return Ok(());
+        }
+    };
+
+    // No roles given means unlinking every role from the invite
+    let mut response = MessageBuilder::new();
+    let roles = if args.is_empty() {
+        None
+    } else {
+        let mut roles = Vec::<u64>::new();
+        for arg in args.iter::<String>().quoted() {
+            let arg = arg.unwrap_or_default();
+            match parse_role(&guild.roles, &arg) {
+                Ok(id) => roles.push(id),
+                // Roles deleted from the guild can still be unlinked by ID
+                Err(RoleError::UnknownId(id)) => roles.push(id),
+                Err(why) => {
+                    response.push_line_safe(why.to_string());
+                }
+            }
+        }
+        Some(roles)
+    };
+
+    if roles.as_ref().is_none_or(|roles| !roles.is_empty()) {
+        response.push(unlink_roles(store.as_ref(), guild.id, &guild.roles, &invite, roles.as_deref()));
+    }
+    if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
 }
 
-// TODO: Remove as soon as we're rid of JSON...
-// Reason being that this only exists to sync the
-// cached invites with the JSON file, something that
-// will not need to be done after migrating to a proper
-// DB where lookups happen from there, without a cache.
+/// Reconcile the guild's stored invites with its live ones, for
+/// `!invite sync` and `/invite sync`. Returns the reply.
+pub async fn sync_invites(ctx: &Context, guild_id: GuildId) -> String {
+    match crate::reconcile_guild(ctx, guild_id, false).await {
+        Ok(drift) if drift.is_empty() => "The invite store was already in sync.".to_string(),
+        Ok(drift) => format!(
+            "Synced: tracked {} new invites, removed {} that no longer exist and corrected {} use counts.",
+            drift.added.len(), drift.removed.len(), drift.recounted.len()
+        ),
+        Err(why) => {
+            error!(error = %why, "Error syncing invites");
+            "Failed to sync the invite store.".to_string()
+        }
+    }
+}
+
+// Re-fetches the guild's invites and reconciles the store with them, the
+// same way the bot does on connecting and every `reconcile_minutes`. Links are
+// persisted as they are made, so this is only needed if the bot somehow
+// missed an invite being created, used or deleted.
 #[command]
+#[description = "Reconcile the tracked invites with the server's"]
+#[num_args(0)]
+#[bucket = "discord"]
 async fn sync(ctx: &Context, msg: &Message) -> CommandResult {
-    // Serialise the new vector and write it back to file?
-    let data_locked = {
-        let data_read = ctx.data.read().await;
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
 
-        // Clone as the contents of data_locked otherwise go out of scope and get dropped after
-        // this block
-        data_read.get::<InviteTracker>().expect("Expected InviteTracker in data/TypeMap").clone()
+    let reply = sync_invites(ctx, guild_id).await;
+    if let Err(why) = msg.channel_id.say(ctx, reply).await {
+        warn!(error = ?why, "Error sending message");
+    }
+
+    Ok(())
+}
+
+/// Every invite tracked in the guild with its linked roles, label and note,
+/// for `!invite list` and `/invite list`. `unlink` says how to unlink roles
+/// that have been deleted.
+pub fn list_invites(store: &dyn MappingStore, guild_id: GuildId, guild_roles: &HashMap<RoleId, Role>, unlink: &str) -> String {
+    let invites = match store.list_guild(guild_id.0) {
+        Ok(invites) => invites,
+        Err(why) => {
+            error!(error = %why, "Error listing invites");
+            return "Failed to read the invite store.".to_string();
+        }
     };
 
-    let cached_invite_map = data_locked.read().await;
+    // Discord messages are capped at 2000 characters, and notes can be long,
+    // so stop early enough to leave room for the lines below
+    const SHOWN: usize = 15;
+    const MAX_LENGTH: usize = 1700;
+    let mut response = MessageBuilder::new();
+    response.push_bold_line("Active invites:");
 
-    if let Ok(db_path) = env::var("JSON_PATH") {
-        let f = fs::File::create(db_path)
-            .expect("Failed to create new file");
-        let mut roles_to_write = Vec::<InviteRoles>::new();
-        for (code, (roles, _uses)) in cached_invite_map.iter() {
-            roles_to_write.push(InviteRoles { code: code.to_string(), roles: roles.to_vec() });
+    let mut shown = 0;
+    for inv in invites.iter().take(SHOWN) {
+        let mut entry = MessageBuilder::new();
+        match &inv.label {
+            Some(label) => entry.push_bold_safe(label.as_str()).push(format!(" ({})", inv.code)),
+            None => entry.push(inv.code.as_str()),
+        };
+        if let Some(inviter) = inv.inviter {
+            entry.push(format!(" by {}", UserId(inviter).mention()));
         }
-        println!("{:?}", roles_to_write);
-        serde_json::to_writer_pretty(f, &roles_to_write)
-            .expect("Failed to write updated JSON");
-    } else {
-        if let Err(why) = msg.channel_id.say(ctx, "Could not find DB path. Ignoring...").await {
-            println!("Error sending message: {:?}", why);
+        if let Some(created_at) = inv.created_at {
+            entry.push(format!(" on <t:{}:d>", created_at));
+        }
+        entry.push(": ");
+        if inv.roles.is_empty() {
+            entry.push_italic_line("No roles linked");
+        } else {
+            entry.push_line(describe_roles(guild_roles, &inv.roles));
+        }
+        if let Some(note) = &inv.note {
+            entry.push_quote_line_safe(note.as_str());
         }
+
+        let entry = entry.build();
+        if response.0.len() + entry.len() > MAX_LENGTH {
+            break;
+        }
+        response.push(entry);
+        shown += 1;
+    }
+    if invites.len() > shown {
+        response.push_italic_line(format!("...and {} more invites", invites.len() - shown));
     }
 
-    Ok(())
+    let stale = invites.iter().map(|inv| resolve_roles(guild_roles, &inv.roles).1.len()).sum::<usize>();
+    if stale > 0 {
+        response.push_line("");
+        response.push_italic_line(format!("{} linked roles no longer exist. Remove them with {}", stale, unlink));
+    }
+    response.build()
 }
 
 #[command]
+#[description = "List the tracked invites with their roles, labels and notes"]
 async fn list(ctx: &Context, msg: &Message) -> CommandResult {
-    let data_locked = {
+    let store = {
         let data_read = ctx.data.read().await;
 
-        // Clone as the contents of data_locked otherwise go out of scope and get dropped after
+        // Clone as the contents of data_read otherwise go out of scope and get dropped after
         // this block
         data_read.get::<InviteTracker>().expect("Expected InviteTracker in data/TypeMap").clone()
     };
 
-    let invites = data_locked.read().await;
+    let guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
+
+    let unlink = format!("{}invite unlink <invite-code> <role-id>", prefix(ctx).await);
+    let response = list_invites(store.as_ref(), guild.id, &guild.roles, &unlink);
+    if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
+
+/// The free-text fields of a tracked invite, edited by `!invite label` and
+/// `!invite note`.
+#[derive(Clone, Copy)]
+enum Annotation {
+    Label,
+    Note,
+}
+
+async fn annotate(ctx: &Context, msg: &Message, mut args: Args, annotation: Annotation) -> CommandResult {
+    let store = {
+        let data = ctx.data.read().await;
+        data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+    };
+
+    let name = match annotation {
+        Annotation::Label => "label",
+        Annotation::Note => "note",
+    };
+    let usage = format!("{}invite {} <invite-code> [text]", prefix(ctx).await, name);
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
+
+    let invite = match args.single_quoted::<String>() {
+        Ok(invite) => invite,
+        Err(_) => {
+            if let Err(why) = msg.channel_id.say(&ctx, format!("Invite code required: {}", usage)).await {
+                warn!(error = ?why, "Error sending message");
+            }
+            return Ok(());
+        }
+    };
+
+    match store.get(guild_id.0, &invite) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            if let Err(why) = msg.channel_id.say(&ctx, format!("Invite {} is not tracked.", invite)).await {
+                warn!(error = ?why, "Error sending message");
+            }
+            return Ok(());
+        }
+        Err(why) => {
+            error!(invite = %invite, error = %why, "Error reading invite");
+            return Ok(());
+        }
+    }
+
+    // The rest of the message is the text, no text clears it
+    let text = args.rest().trim().trim_matches('"').trim();
+    let text = (!text.is_empty()).then_some(text);
+    let saved = match annotation {
+        Annotation::Label => store.set_label(&invite, text),
+        Annotation::Note => store.set_note(&invite, text),
+    };
+    if let Err(why) = saved {
+        error!(invite = %invite, field = name, error = %why, "Error saving invite details");
+        if let Err(why) = msg.channel_id.say(&ctx, format!("Failed to save the {}, nothing was changed.", name)).await {
+            warn!(error = ?why, "Error sending message");
+        }
+        return Ok(());
+    }
 
     let mut response = MessageBuilder::new();
-    response.push_bold_line("Active invites:");
+    match text {
+        Some(text) => response.push(format!("Set the {} of {} to: ", name, invite)).push_safe(text),
+        None => response.push(format!("Cleared the {} of {}.", name, invite)),
+    };
+    if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
 
-    // Make an iterator out of the RwLock
-    for (code, (roles, _uses)) in invites.iter() {
-        response.push(code.to_string() + ": ");
-        if roles.is_empty() {
-            response.push_italic_line("No roles linked");
-        } else {
-            response.push_line(roles.iter().map(|r| r.name.to_string()).collect::<Vec<String>>().join(", "));
+// !invite label <invite-code> [text]
+// Gives the invite a human-readable name shown in !invite list, or clears it.
+#[command]
+#[description = "Give an invite a human-readable name, or clear it"]
+#[usage = "<invite-code> [text]"]
+#[example = "abc123 Career fair"]
+#[min_args(1)]
+async fn label(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    annotate(ctx, msg, args, Annotation::Label).await
+}
+
+// !invite note <invite-code> [text]
+// Keeps a free-text note on the invite, or clears it.
+#[command]
+#[description = "Keep a free-text note on an invite, or clear it"]
+#[usage = "<invite-code> [text]"]
+#[example = "abc123 Handed out at the career fair"]
+#[min_args(1)]
+async fn note(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    annotate(ctx, msg, args, Annotation::Note).await
+}
+
+// !invite config                          Show this server's settings
+// !invite config autoassign <on|off>      Assign linked roles to new members
+// !invite config log <#channel|off>       Report which invite new members used
+#[command]
+#[description = "Show or change this server's settings"]
+#[usage = "[autoassign <on|off> | log <#channel|off>]"]
+#[example = "log #joins"]
+#[max_args(2)]
+async fn config(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = {
+        let data = ctx.data.read().await;
+        data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+    };
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
+
+    let mut settings = match store.guild_settings(guild_id.0) {
+        Ok(settings) => settings,
+        Err(why) => {
+            error!(error = %why, "Error reading guild settings");
+            return Ok(());
+        }
+    };
+
+    if !args.is_empty() {
+        let key = args.single::<String>().unwrap_or_default();
+        let value = args.single::<String>().unwrap_or_default();
+        match (key.to_lowercase().as_str(), value.to_lowercase().as_str()) {
+            ("autoassign", "on") => settings.auto_assign = true,
+            ("autoassign", "off") => settings.auto_assign = false,
+            ("log", "off") => settings.log_channel = None,
+            ("log", _) => match value.parse::<ChannelId>() {
+                Ok(channel) => settings.log_channel = Some(channel.0),
+                Err(_) => {
+                    if let Err(why) = msg.channel_id.say(&ctx, format!("No channel {} found.", value)).await {
+                        warn!(error = ?why, "Error sending message");
+                    }
+                    return Ok(());
+                }
+            },
+            _ => {
+                if let Err(why) = msg.channel_id.say(&ctx, format!("Usage: {}invite config [autoassign <on|off> | log <#channel|off>]", prefix(ctx).await)).await {
+                    warn!(error = ?why, "Error sending message");
+                }
+                return Ok(());
+            }
+        }
+
+        if let Err(why) = store.set_guild_settings(guild_id.0, &settings) {
+            error!(error = %why, "Error saving guild settings");
+            if let Err(why) = msg.channel_id.say(&ctx, "Failed to save the settings, nothing was changed.").await {
+                warn!(error = ?why, "Error sending message");
+            }
+            return Ok(());
         }
-        // println!("{:?}",invites);
     }
 
+    let mut response = MessageBuilder::new();
+    response.push_bold_line("Invite settings:");
+    response.push_line(format!("Assign linked roles on join: {}", if settings.auto_assign { "on" } else { "off" }));
+    response.push("Join log channel: ");
+    match settings.log_channel {
+        Some(channel) => response.push_line(ChannelId(channel).mention()),
+        None => response.push_italic_line("off"),
+    };
+
     if let Err(why) = msg.channel_id.say(&ctx, &response).await {
-        println!("Error checking invites: {:?}", why);
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
+
+// !invite history                 The most recent joins
+// !invite history <invite-code>   Who joined through an invite
+// !invite history <@user>         Which invite a member joined through
+#[command]
+#[description = "Show the most recent joins, through an invite or of a member"]
+#[usage = "[invite-code | @user]"]
+#[example = "abc123"]
+#[max_args(1)]
+async fn history(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = {
+        let data = ctx.data.read().await;
+        data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+    };
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
+
+    // Mentions and raw IDs are users, anything else is an invite code. Invite
+    // codes can be all digits too, so tracked invites are looked up first.
+    let filter = match args.single_quoted::<String>() {
+        Ok(arg) => match store.get(guild_id.0, &arg) {
+            Ok(Some(_)) => JoinFilter::Invite(arg),
+            Ok(None) => match arg.parse::<UserId>() {
+                Ok(user_id) => JoinFilter::User(user_id.0),
+                Err(_) => JoinFilter::Invite(arg),
+            },
+            Err(why) => {
+                error!(invite = %arg, error = %why, "Error reading invite");
+                return Ok(());
+            }
+        },
+        Err(_) => JoinFilter::All,
+    };
+
+    let joins = match store.joins(guild_id.0, &filter) {
+        Ok(joins) => joins,
+        Err(why) => {
+            error!(error = %why, "Error reading the join log");
+            return Ok(());
+        }
+    };
+
+    // Discord messages are capped at 2000 characters
+    const SHOWN: usize = 15;
+    let mut response = MessageBuilder::new();
+    match &filter {
+        JoinFilter::All => response.push_bold_line("Recent joins:"),
+        JoinFilter::Invite(code) => response.push_bold_line(format!("Joins through {}:", code)),
+        JoinFilter::User(user_id) => response.push_bold_line(format!("Joins of {}:", UserId(*user_id).mention())),
+    };
+    if joins.is_empty() {
+        response.push_italic_line("No joins recorded");
+    }
+    for join in joins.iter().take(SHOWN) {
+        response.push(format!("<t:{}:f> {} ", join.joined_at, UserId(join.user_id).mention()));
+        match &join.invite {
+            Some(code) => response.push(format!("via {}", code)),
+            None => response.push("via an unknown invite"),
+        };
+        if let Some(inviter) = join.inviter {
+            response.push(format!(" (created by {})", UserId(inviter).mention()));
+        }
+        if !join.roles.is_empty() {
+            let roles = join.roles.iter().map(|r| RoleId(*r).mention().to_string()).collect::<Vec<String>>();
+            response.push(format!(", got {}", roles.join(", ")));
+        }
+        if join.confidence != Confidence::Certain {
+            response.push_italic(format!(" [{}]", join.confidence.as_str().replace('_', " ")));
+        }
+        response.push_line("");
+    }
+    if joins.len() > SHOWN {
+        response.push_italic_line(format!("...and {} earlier joins", joins.len() - SHOWN));
+    }
+
+    if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+        warn!(error = ?why, "Error sending message");
+    }
+    Ok(())
+}
+
+// !invite stats                  Joins, retention and leaderboards for the guild
+// !invite stats <invite-code>    Joins and retention of a single invite
+#[command]
+#[description = "Sum up the joins, retention and leaderboards, or those of a single invite"]
+#[usage = "[invite-code]"]
+#[example = "abc123"]
+#[max_args(1)]
+async fn stats(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = {
+        let data = ctx.data.read().await;
+        data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+    };
+
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => {
+            debug!("Not in a guild");
+            return Ok(());
+        }
+    };
+
+    let code = args.single::<String>().ok();
+    let filter = code.clone().map_or(JoinFilter::All, JoinFilter::Invite);
+    let (joins, tracked) = match (store.joins(guild_id.0, &filter), store.list_guild(guild_id.0)) {
+        (Ok(joins), Ok(tracked)) => (joins, tracked),
+        (Err(why), _) | (_, Err(why)) => {
+            error!(error = %why, "Error reading the join log");
+            return Ok(());
+        }
+    };
+
+    const WEEKS: usize = 8;
+    const TOP: usize = 5;
+    let summary = Summary::of(&joins);
+    let mut response = MessageBuilder::new();
+    match &code {
+        Some(code) => {
+            response.push_bold_line(format!("Stats for invite {}:", code));
+            match tracked.iter().find(|inv| &inv.code == code) {
+                Some(inv) => {
+                    response.push(format!("Used {} times", inv.uses));
+                    if inv.max_uses > 0 {
+                        response.push(format!(" out of {}", inv.max_uses));
+                    }
+                    if let Some(inviter) = inv.inviter {
+                        response.push(format!(", created by {}", UserId(inviter).mention()));
+                    }
+                    response.push_line("");
+                }
+                None => {
+                    response.push_italic_line("Not an active invite");
+                }
+            }
+            response.push_line(format!("Joins: {} recorded", summary.joins));
+        }
+        None => {
+            response.push_bold_line("Invite stats:");
+            response.push_line(format!("Joins: {} recorded, {} attributed to an invite", summary.joins, summary.attributed));
+        }
+    };
+    match summary.retention() {
+        Some(percent) => response.push_line(format!("Retention: {} of {} members are still here ({}%)", summary.retained, summary.attributed, percent)),
+        None => response.push_line("Retention: no attributed joins yet"),
+    };
+
+    // A small bar chart, scaled to the busiest week
+    let weeks = stats::joins_per_week(&joins, Timestamp::now().unix_timestamp(), WEEKS);
+    let peak = weeks.iter().copied().max().unwrap_or(0).max(1);
+    let mut chart = String::new();
+    for (i, count) in weeks.iter().enumerate() {
+        let label = match WEEKS - 1 - i {
+            0 => "this week".to_string(),
+            1 => "1 week ago".to_string(),
+            ago => format!("{} weeks ago", ago),
+        };
+        chart.push_str(&format!("{:>11} {:<20} {}\n", label, "█".repeat(count * 20 / peak), count));
+    }
+    response.push_bold_line("Joins per week:");
+    response.push_codeblock(chart, None);
+
+    if code.is_none() {
+        response.push_bold_line("Top invites:");
+        let invites = stats::leaderboard(&joins, |join| join.invite.clone());
+        if invites.is_empty() {
+            response.push_italic_line("None yet");
+        }
+        for (rank, (code, s)) in invites.iter().take(TOP).enumerate() {
+            response.push(format!("{}. {}: {} joins, {} stayed", rank + 1, code, s.joins, s.retained));
+            if let Some(inv) = tracked.iter().find(|inv| &inv.code == code) {
+                response.push(format!(" ({} uses)", inv.uses));
+            }
+            response.push_line("");
+        }
+
+        response.push_bold_line("Top inviters:");
+        let inviters = stats::leaderboard(&joins, |join| join.inviter);
+        if inviters.is_empty() {
+            response.push_italic_line("None yet");
+        }
+        for (rank, (inviter, s)) in inviters.iter().take(TOP).enumerate() {
+            response.push_line(format!("{}. {}: {} joins, {} stayed", rank + 1, UserId(*inviter).mention(), s.joins, s.retained));
+        }
+    }
+
+    if let Err(why) = msg.channel_id.send_message(&ctx, |m| m.content(&response).allowed_mentions(|am| am.empty_parse())).await {
+        warn!(error = ?why, "Error sending message");
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Roles cannot be built directly, so deserialise one like Discord sends it.
+    fn role(id: u64, name: &str) -> Role {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "guild_id": "1",
+            "color": 0,
+            "hoist": false,
+            "managed": false,
+            "mentionable": false,
+            "name": name,
+            "permissions": "0",
+            "position": 1,
+        })).unwrap()
+    }
+
+    #[test]
+    fn deleted_roles_are_told_apart() {
+        let guild_roles = HashMap::from([(RoleId(20), role(20, "Alumni"))]);
+        let (found, deleted) = resolve_roles(&guild_roles, &[20, 30]);
+        assert_eq!(found.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(), vec!["Alumni"]);
+        assert_eq!(deleted, vec![30]);
+    }
+}
@@ -4,27 +4,80 @@
  * async fn name(Context, Message) -> CommandResult {}
  * No self parameter. They should also return Ok(())
  * TODO: Break these into different files later with pub mod <filename> */
+pub mod admin;
 pub mod invite; 
-use serenity::framework::standard::macros::command;
-use serenity::framework::standard::CommandResult;
+pub mod slash;
+use std::collections::HashSet;
+
+use serenity::framework::standard::macros::{check, command, help};
+use serenity::framework::standard::{help_commands, Args, CommandGroup, CommandOptions, CommandResult, HelpOptions, Reason};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
+use tracing::warn;
 
+use crate::BotConfig;
 
-#[command]
-#[description = "A simple ping command"]
-async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
-    if let Err(why) = msg.channel_id.say(&ctx.http, "Pong!").await {
-        println!("Error: {:?}", why);
+/// Whether a member with `roles` has one of the configured moderator roles,
+/// given by either name or ID.
+pub async fn is_moderator(ctx: &Context, guild_id: GuildId, roles: &[RoleId]) -> bool {
+    let config = {
+        let data = ctx.data.read().await;
+        data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone()
+    };
+    let guild_roles = ctx.cache.guild_field(guild_id, |g| g.roles.clone()).unwrap_or_default();
+
+    roles.iter().any(|id| {
+        config.mod_roles.iter().any(|name| *name == id.0.to_string() || guild_roles.get(id).is_some_and(|role| role.name == *name))
+    })
+}
+
+/// The prefix the `!` commands are configured with, for replies that tell
+/// users which command to run.
+pub async fn prefix(ctx: &Context) -> String {
+    let data = ctx.data.read().await;
+    data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").prefix.clone()
+}
+
+// Lets members with one of the configured moderator roles through. Guards the
+// !invite commands.
+#[check]
+#[name = "Moderator"]
+async fn moderator_check(ctx: &Context, msg: &Message, _: &mut Args, _: &CommandOptions) -> Result<(), Reason> {
+    let guild_id = msg.guild_id.ok_or_else(|| Reason::User("Only usable in a server".to_string()))?;
+    let member = msg.member(ctx).await.map_err(|why| Reason::Log(format!("Could not get the member: {:?}", why)))?;
+
+    if is_moderator(ctx, guild_id, &member.roles).await {
+        Ok(())
+    } else {
+        Err(Reason::User("You need a moderator role to use this command".to_string()))
+    }
+}
+
+
+// !help               Every group and command, with descriptions
+// !help <command>     A command's usage and examples
+#[help]
+#[individual_command_tip = "Add a command's name for its usage and examples, e.g. `help invite link`."]
+#[command_not_found_text = "No command `{}` found."]
+#[max_levenshtein_distance(3)]
+#[lacking_role = "Hide"]
+#[lacking_ownership = "Hide"]
+#[strikethrough_commands_tip_in_guild = "~~Struck out~~ commands need a moderator role."]
+async fn help(ctx: &Context, msg: &Message, args: Args, options: &'static HelpOptions, groups: &[&'static CommandGroup], owners: HashSet<UserId>) -> CommandResult {
+    if let Err(why) = help_commands::with_embeds(ctx, msg, args, options, groups, owners).await {
+        warn!(error = ?why, "Error sending message");
     }
     Ok(())
 }
 
+/// What `!ping` and `/ping` reply with.
+pub const PONG: &str = "Pong!";
+
 #[command]
-async fn company(ctx: &Context, msg: &Message) -> CommandResult {
-    println!("Company called");
-    if let Err(why) = msg.channel_id.say(&ctx.http, "Hi company x").await {
-        println!("Error: {:?}", why);
+#[description = "A simple ping command"]
+async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
+    if let Err(why) = msg.channel_id.say(&ctx.http, PONG).await {
+        warn!(error = ?why, "Error sending message");
     }
     Ok(())
 }
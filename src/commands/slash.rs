@@ -0,0 +1,284 @@
+/* Slash commands, registered in every guild the bot serves when it connects.
+ * `/invite link|unlink|list|sync|create` and `/ping` run the same code as
+ * their `!` counterparts, but take typed options (Discord offers pickers for
+ * roles and channels, and suggests tracked invite codes) and reply so only
+ * whoever ran the command can see it. */
+use serenity::builder::{CreateApplicationCommandOption, CreateApplicationCommands};
+use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::interaction::application_command::{ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue};
+use serenity::model::application::interaction::autocomplete::AutocompleteInteraction;
+use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::prelude::*;
+use serenity::prelude::*;
+use tracing::{debug, warn};
+
+use super::invite::{self, NewInvite, MAX_INVITE_AGE, MAX_INVITE_USES};
+use super::{is_moderator, PONG};
+use crate::InviteTracker;
+
+// A role option takes a single role, so commands linking roles take several
+const ROLE_OPTIONS: [&str; 5] = ["role", "role2", "role3", "role4", "role5"];
+
+// The most suggestions Discord shows for an option, and the longest each may be
+const MAX_CHOICES: usize = 25;
+const MAX_CHOICE_NAME: usize = 100;
+
+/// Register the slash commands in a guild, replacing any registered before.
+pub async fn register(ctx: &Context, guild_id: GuildId) -> serenity::Result<()> {
+    guild_id.set_application_commands(&ctx.http, define).await?;
+    Ok(())
+}
+
+fn define(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| c.name("ping").description("Check that the bot is responding"))
+        .create_application_command(|c| {
+            c.name("invite")
+                .description("Link invites to roles that are assigned on member join")
+                .create_option(|o| {
+                    o.name("link")
+                        .description("Link roles to a tracked invite")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(invite_option);
+                    role_options(o, true)
+                })
+                .create_option(|o| {
+                    o.name("unlink")
+                        .description("Unlink roles from an invite, or all of its roles if none are given")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(invite_option);
+                    role_options(o, false)
+                })
+                .create_option(|o| {
+                    o.name("list")
+                        .description("List the tracked invites and their roles")
+                        .kind(CommandOptionType::SubCommand)
+                })
+                .create_option(|o| {
+                    o.name("sync")
+                        .description("Reconcile the tracked invites with the server's")
+                        .kind(CommandOptionType::SubCommand)
+                })
+                .create_option(|o| {
+                    o.name("create")
+                        .description("Create an invite with roles linked to it")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(|c| {
+                            c.name("channel")
+                                .description("The channel the invite leads to")
+                                .kind(CommandOptionType::Channel)
+                                .channel_types(&[ChannelType::Text, ChannelType::News])
+                                .required(true)
+                        })
+                        .create_sub_option(|h| {
+                            h.name("hours")
+                                .description("Hours until the invite expires, never if not given")
+                                .kind(CommandOptionType::Integer)
+                                .min_int_value(1)
+                                .max_int_value(MAX_INVITE_AGE / (60 * 60))
+                        })
+                        .create_sub_option(|u| {
+                            u.name("uses")
+                                .description("How many times the invite can be used, unlimited if not given")
+                                .kind(CommandOptionType::Integer)
+                                .min_int_value(1)
+                                .max_int_value(MAX_INVITE_USES)
+                        })
+                        .create_sub_option(|l| {
+                            l.name("label")
+                                .description("A name for the invite, e.g. the event it is for")
+                                .kind(CommandOptionType::String)
+                        });
+                    role_options(o, false)
+                })
+        })
+}
+
+fn invite_option(o: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    o.name("invite")
+        .description("The invite code")
+        .kind(CommandOptionType::String)
+        .required(true)
+        .set_autocomplete(true)
+}
+
+/// Add the role options to a subcommand, the first of which is required if
+/// `required` is set.
+fn role_options(o: &mut CreateApplicationCommandOption, required: bool) -> &mut CreateApplicationCommandOption {
+    for (i, name) in ROLE_OPTIONS.iter().enumerate() {
+        o.create_sub_option(|r| {
+            r.name(name)
+                .description(if i == 0 { "A role" } else { "Another role" })
+                .kind(CommandOptionType::Role)
+                .required(required && i == 0)
+        });
+    }
+    o
+}
+
+/// The name of the command as the `!` commands call it, for logs and metrics:
+/// the subcommand for `/invite`.
+pub fn name(command: &ApplicationCommandInteraction) -> &str {
+    match command.data.options.first() {
+        Some(sub) if command.data.name == "invite" => &sub.name,
+        _ => &command.data.name,
+    }
+}
+
+/// Run a slash command and reply to it.
+pub async fn run(ctx: &Context, command: &ApplicationCommandInteraction) -> serenity::Result<()> {
+    let guild_id = match command.guild_id {
+        Some(guild_id) => guild_id,
+        None => return respond(ctx, command, "Only usable in a server").await,
+    };
+    let sub = match command.data.options.first() {
+        Some(sub) if command.data.name == "invite" => sub,
+        _ if command.data.name == "ping" => return respond(ctx, command, PONG).await,
+        _ => {
+            debug!(command = %command.data.name, "Unknown command");
+            return respond(ctx, command, "Unknown command, the bot may have been updated since it was registered.").await;
+        }
+    };
+
+    let member_roles = command.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or_default();
+    if !is_moderator(ctx, guild_id, member_roles).await {
+        return respond(ctx, command, "You need a moderator role to use this command").await;
+    }
+
+    // Syncing and creating invites call Discord and may take longer than the
+    // three seconds it waits for a reply, so reply once done
+    command.create_interaction_response(&ctx.http, |r| {
+        r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            .interaction_response_data(|d| d.ephemeral(true))
+    }).await?;
+
+    let store = {
+        let data = ctx.data.read().await;
+        data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+    };
+    let guild_roles = invite::guild_roles(ctx, guild_id);
+    let code = match option(sub, "invite") {
+        Some(CommandDataOptionValue::String(code)) => code.as_str(),
+        _ => "",
+    };
+    let roles = ROLE_OPTIONS.iter()
+        .filter_map(|name| match option(sub, name) {
+            Some(CommandDataOptionValue::Role(role)) => Some(role.id.0),
+            _ => None,
+        })
+        .collect::<Vec<u64>>();
+
+    let reply = match sub.name.as_str() {
+        "link" => {
+            // Only link the roles the bot can actually give
+            let (grantable, mut reply) = invite::grantable_roles(ctx, guild_id, &roles).await;
+            if !grantable.is_empty() {
+                reply.push(invite::link_roles(store.as_ref(), guild_id, &guild_roles, code, &grantable));
+            }
+            reply.join("\n")
+        }
+        // No roles given means unlinking every role from the invite
+        "unlink" => invite::unlink_roles(store.as_ref(), guild_id, &guild_roles, code, (!roles.is_empty()).then_some(roles.as_slice())),
+        // Deleted roles cannot be picked, so unlink them all and link the rest again
+        "list" => invite::list_invites(store.as_ref(), guild_id, &guild_roles, "/invite unlink without any roles, then link the others again"),
+        "sync" => invite::sync_invites(ctx, guild_id).await,
+        "create" => match option(sub, "channel") {
+            Some(CommandDataOptionValue::Channel(channel)) => {
+                // Nor create an invite linked to roles the bot cannot give
+                let (grantable, refused) = invite::grantable_roles(ctx, guild_id, &roles).await;
+                if grantable.len() < roles.len() {
+                    return edit(ctx, command, format!("{}\nNo invite was created.", refused.join("\n"))).await;
+                }
+                let new = NewInvite {
+                    channel: channel.id,
+                    max_age: integer(sub, "hours") * 60 * 60,
+                    max_uses: integer(sub, "uses"),
+                    roles,
+                    label: match option(sub, "label") {
+                        Some(CommandDataOptionValue::String(label)) => Some(label.clone()),
+                        _ => None,
+                    },
+                };
+                invite::create_invite(ctx, store.as_ref(), guild_id, &guild_roles, command.user.id, new).await
+            }
+            _ => "A channel is required".to_string(),
+        },
+        name => {
+            debug!(command = %name, "Unknown subcommand");
+            "Unknown command, the bot may have been updated since it was registered.".to_string()
+        }
+    };
+
+    edit(ctx, command, reply).await
+}
+
+/// Replace the deferred reply with `content`.
+async fn edit(ctx: &Context, command: &ApplicationCommandInteraction, content: String) -> serenity::Result<()> {
+    command.edit_original_interaction_response(&ctx.http, |r| r.content(content).allowed_mentions(|am| am.empty_parse())).await?;
+    Ok(())
+}
+
+/// Reply so only whoever ran the command can see it. Mentions in the reply
+/// never ping anyone.
+pub async fn respond(ctx: &Context, command: &ApplicationCommandInteraction, content: impl ToString) -> serenity::Result<()> {
+    command.create_interaction_response(&ctx.http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.content(content).ephemeral(true).allowed_mentions(|am| am.empty_parse()))
+    }).await
+}
+
+fn option<'a>(sub: &'a CommandDataOption, name: &str) -> Option<&'a CommandDataOptionValue> {
+    sub.options.iter().find(|o| o.name == name).and_then(|o| o.resolved.as_ref())
+}
+
+/// An integer option, 0 if not given. Discord enforces the bounds set on it.
+fn integer(sub: &CommandDataOption, name: &str) -> u64 {
+    match option(sub, name) {
+        Some(CommandDataOptionValue::Integer(n)) => u64::try_from(*n).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Suggest the guild's tracked invites whose code or label contains what has
+/// been typed so far. Only moderators get suggestions.
+pub async fn autocomplete(ctx: &Context, autocomplete: &AutocompleteInteraction) -> serenity::Result<()> {
+    let guild_id = match autocomplete.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let member_roles = autocomplete.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or_default();
+    let invites = if is_moderator(ctx, guild_id, member_roles).await {
+        let store = {
+            let data = ctx.data.read().await;
+            data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+        };
+        store.list_guild(guild_id.0).unwrap_or_else(|why| {
+            warn!(error = %why, "Error listing invites");
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+
+    let typed = autocomplete.data.options.iter()
+        .flat_map(|sub| sub.options.iter())
+        .find(|o| o.focused)
+        .and_then(|o| o.value.as_ref())
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let matches = invites.iter().filter(|inv| {
+        inv.code.to_lowercase().contains(&typed) || inv.label.as_ref().is_some_and(|label| label.to_lowercase().contains(&typed))
+    });
+
+    autocomplete.create_autocomplete_response(&ctx.http, |r| {
+        for inv in matches.take(MAX_CHOICES) {
+            let name = match &inv.label {
+                Some(label) => format!("{} ({})", label, inv.code),
+                None => inv.code.clone(),
+            };
+            r.add_string_choice(name.chars().take(MAX_CHOICE_NAME).collect::<String>(), inv.code.clone());
+        }
+        r
+    }).await
+}
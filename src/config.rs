@@ -0,0 +1,507 @@
+/* Configuration, read from a TOML file with environment variables on top.
+ * Everything is checked before the client connects, and every problem found
+ * is reported at once rather than one per restart. See `config.example.toml`
+ * for the file format. */
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serenity::model::gateway::GatewayIntents;
+use serenity::model::permissions::Permissions;
+
+use crate::roles::{DenyList, DANGEROUS_PERMISSIONS};
+
+/// Read when no config file is given, if it exists.
+pub const DEFAULT_PATH: &str = "config.toml";
+
+const LOG_LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+const LOG_FORMATS: &[&str] = &["text", "pretty", "json"];
+
+// Roles granting these are never linked to invites, unless configured otherwise
+const DEFAULT_DENY_PERMISSIONS: &[&str] = &["administrator", "manage_guild", "manage_roles"];
+
+// Reconciling less often than weekly would hardly catch anything
+const MAX_RECONCILE_MINUTES: u64 = 7 * 24 * 60;
+
+// Backing up less often than yearly is as good as only on start
+const MAX_BACKUP_HOURS: u64 = 365 * 24;
+
+// The bot cannot work without being told about guilds and their roles,
+// members joining and leaving, invites, and the messages carrying commands.
+const REQUIRED_INTENTS: &[&str] = &["guilds", "guild_members", "guild_invites", "guild_messages", "message_content"];
+
+/// What can be set in the config file. Everything is optional here; defaults
+/// and checks are applied once the environment has been read too.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    token: Option<String>,
+    storage: StorageFile,
+    guilds: Option<Vec<u64>>,
+    prefix: Option<String>,
+    delimiters: Option<Vec<String>>,
+    mod_roles: Option<Vec<String>>,
+    deny_roles: Option<Vec<String>>,
+    deny_permissions: Option<Vec<String>>,
+    intents: Option<Vec<String>>,
+    reconcile_minutes: Option<u64>,
+    logging: LoggingFile,
+    http: HttpFile,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct StorageFile {
+    path: Option<PathBuf>,
+    auto_create: Option<bool>,
+    legacy_json: Option<PathBuf>,
+    backups: Option<u64>,
+    backup_hours: Option<u64>,
+    backup_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingFile {
+    level: Option<String>,
+    format: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct HttpFile {
+    listen: Option<String>,
+}
+
+/// The validated configuration. Deliberately not `Debug`, so the token
+/// cannot end up in a log by accident.
+#[derive(Clone)]
+pub struct Config {
+    pub token: String,
+    pub storage: StorageConfig,
+    /// The guilds the bot serves; empty means every guild it is in.
+    pub guilds: Vec<u64>,
+    pub prefix: String,
+    pub delimiters: Vec<String>,
+    /// Names or IDs of the roles allowed to use the `!invite` commands.
+    pub mod_roles: Vec<String>,
+    /// The roles that may never be linked to an invite.
+    pub deny: DenyList,
+    pub intents: GatewayIntents,
+    /// How often to reconcile the stored invites with every guild, on top of
+    /// doing so on connecting and resuming. `None` to only do the latter.
+    pub reconcile_interval: Option<Duration>,
+    pub logging: LoggingConfig,
+    /// Where to serve the monitoring endpoints, if anywhere.
+    pub http_listen: Option<SocketAddr>,
+    /// The config file this was read from, if any.
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageConfig {
+    /// The invite store, see `store::open`.
+    pub path: PathBuf,
+    /// Whether a missing store may be created on startup.
+    pub auto_create: bool,
+    /// The old JSON "database", imported into an empty store.
+    pub legacy_json: Option<PathBuf>,
+    /// How many backups of the store to keep, 0 for none.
+    pub backups: usize,
+    /// How often to back the store up, on top of doing so on every start.
+    pub backup_interval: Option<Duration>,
+    /// Where to keep the backups, next to the store if not set.
+    pub backup_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggingConfig {
+    /// The most verbose level logged by the bot itself. Other crates only
+    /// log warnings and errors.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One line per event, for reading in a terminal or journal.
+    Text,
+    /// Several lines per event, with every field on its own line.
+    Pretty,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+/// Every problem found in the configuration.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for why in &self.0 {
+            write!(f, "\n  - {}", why)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Read the config file at `path`, or `DEFAULT_PATH` if none is given and
+    /// it exists, and apply the environment on top of it. The token may only
+    /// be left out when `needs_token` is false.
+    pub fn load(path: Option<&Path>, needs_token: bool) -> Result<Config, ConfigError> {
+        let mut errors = Vec::new();
+        let path = path.or_else(|| Some(Path::new(DEFAULT_PATH)).filter(|p| p.exists()));
+        let file = match path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => toml::from_str(&contents).unwrap_or_else(|why| {
+                    errors.push(format!("{}: {}", path.display(), why));
+                    ConfigFile::default()
+                }),
+                Err(why) => {
+                    errors.push(format!("Could not read {}: {}", path.display(), why));
+                    ConfigFile::default()
+                }
+            },
+            None => ConfigFile::default(),
+        };
+
+        Config::resolve(file, |name| env::var(name).ok(), needs_token, errors)
+            .map(|config| Config { source: path.map(Path::to_path_buf), ..config })
+    }
+
+    /// The settings changed in `new` that are only applied on startup, so
+    /// take a restart to change.
+    pub fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("token", self.token != new.token),
+            ("storage", self.storage != new.storage),
+            ("prefix", self.prefix != new.prefix),
+            ("delimiters", self.delimiters != new.delimiters),
+            ("intents", self.intents != new.intents),
+            ("reconcile_minutes", self.reconcile_interval != new.reconcile_interval),
+            ("logging", self.logging != new.logging),
+            ("http.listen", self.http_listen != new.http_listen),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// Whether the bot should act in `guild_id`.
+    pub fn serves(&self, guild_id: u64) -> bool {
+        self.guilds.is_empty() || self.guilds.contains(&guild_id)
+    }
+
+    /// Apply the environment variables on top of the file and check the
+    /// result, adding every problem to `errors`.
+    fn resolve(
+        file: ConfigFile,
+        var: impl Fn(&str) -> Option<String>,
+        needs_token: bool,
+        mut errors: Vec<String>,
+    ) -> Result<Config, ConfigError> {
+        let token = var("DISCORD_TOKEN").or(file.token).unwrap_or_default();
+        if needs_token && token.trim().is_empty() {
+            errors.push("No Discord token, set DISCORD_TOKEN or `token`".to_string());
+        }
+
+        let path = var("DB_PATH").map(PathBuf::from).or(file.storage.path);
+        if path.is_none() {
+            errors.push("No invite store, set DB_PATH or `storage.path`".to_string());
+        }
+        let auto_create = match var("DB_AUTO_CREATE") {
+            Some(value) => parse_bool(&value).unwrap_or_else(|| {
+                errors.push(format!("DB_AUTO_CREATE must be true or false, not {}", value));
+                false
+            }),
+            None => file.storage.auto_create.unwrap_or(false),
+        };
+        let legacy_json = var("JSON_PATH").map(PathBuf::from).or(file.storage.legacy_json);
+        let backups = number(&mut errors, "DB_BACKUPS", var("DB_BACKUPS"), file.storage.backups, 7) as usize;
+        let backup_hours = number(&mut errors, "DB_BACKUP_HOURS", var("DB_BACKUP_HOURS"), file.storage.backup_hours, 24);
+        let backup_interval = period(&mut errors, "DB_BACKUP_HOURS", backup_hours, 60 * 60, MAX_BACKUP_HOURS);
+        let backup_dir = var("DB_BACKUP_DIR").map(PathBuf::from).or(file.storage.backup_dir);
+
+        let guilds = match var("GUILD_IDS") {
+            Some(value) => split_list(&value).into_iter()
+                .filter_map(|id| match id.parse::<u64>() {
+                    Ok(id) => Some(id),
+                    Err(_) => {
+                        errors.push(format!("GUILD_IDS: {} is not a guild ID", id));
+                        None
+                    }
+                })
+                .collect(),
+            None => file.guilds.unwrap_or_default(),
+        };
+        if guilds.contains(&0) {
+            errors.push("0 is not a guild ID".to_string());
+        }
+
+        let prefix = var("COMMAND_PREFIX").or(file.prefix).unwrap_or_else(|| "!".to_string());
+        let prefix_valid = !prefix.is_empty() && !prefix.contains(char::is_whitespace);
+        if !prefix_valid {
+            errors.push(format!("The command prefix must be non-empty and without spaces, not {:?}", prefix));
+        }
+        let delimiters = file.delimiters.unwrap_or_else(|| vec![", ".to_string(), ",".to_string(), " ".to_string()]);
+        if delimiters.is_empty() || delimiters.iter().any(String::is_empty) {
+            errors.push("`delimiters` must be a list of non-empty strings".to_string());
+        }
+
+        let mod_roles = var("MOD_ROLES").map(|value| split_list(&value)).or(file.mod_roles)
+            .unwrap_or_else(|| vec!["Mod".to_string()]);
+        if mod_roles.is_empty() {
+            // The prefix is reported on its own if it is unusable
+            let prefix = if prefix_valid { prefix.as_str() } else { "!" };
+            errors.push(format!("No moderator roles, nobody would be able to use the {}invite commands", prefix));
+        }
+
+        let deny_roles = var("DENY_ROLES").map(|value| split_list(&value)).or(file.deny_roles).unwrap_or_default();
+        let mut deny_permissions = Permissions::empty();
+        let permission_names = var("DENY_PERMISSIONS").map(|value| split_list(&value)).or(file.deny_permissions)
+            .unwrap_or_else(|| DEFAULT_DENY_PERMISSIONS.iter().map(|name| name.to_string()).collect());
+        for name in &permission_names {
+            match DANGEROUS_PERMISSIONS.iter().find(|(known, _)| known == name) {
+                Some((_, permission)) => deny_permissions |= *permission,
+                None => errors.push(format!(
+                    "Unknown permission {} in deny_permissions, use one of {}",
+                    name, DANGEROUS_PERMISSIONS.iter().map(|(known, _)| *known).collect::<Vec<&str>>().join(", ")
+                )),
+            }
+        }
+
+        let intent_names = var("INTENTS").map(|value| split_list(&value)).or(file.intents)
+            .unwrap_or_else(|| REQUIRED_INTENTS.iter().map(|name| name.to_string()).collect());
+        let mut intents = GatewayIntents::empty();
+        for name in &intent_names {
+            match intent(name) {
+                Some(intent) => intents |= intent,
+                None => errors.push(format!("Unknown intent {}", name)),
+            }
+        }
+        let missing = REQUIRED_INTENTS.iter()
+            .filter(|name| intent(name).is_some_and(|required| !intents.contains(required)))
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            errors.push(format!("The bot needs the {} intents", missing.join(", ")));
+        }
+
+        let reconcile_minutes = number(&mut errors, "RECONCILE_MINUTES", var("RECONCILE_MINUTES"), file.reconcile_minutes, 30);
+        let reconcile_interval = period(&mut errors, "RECONCILE_MINUTES", reconcile_minutes, 60, MAX_RECONCILE_MINUTES);
+
+        let log_level = var("LOG_LEVEL").or(file.logging.level).unwrap_or_else(|| "info".to_string());
+        if !LOG_LEVELS.contains(&log_level.as_str()) {
+            errors.push(format!("Unknown log level {}, use one of {}", log_level, LOG_LEVELS.join(", ")));
+        }
+        let log_format = match var("LOG_FORMAT").or(file.logging.format).as_deref() {
+            None | Some("text") => LogFormat::Text,
+            Some("pretty") => LogFormat::Pretty,
+            Some("json") => LogFormat::Json,
+            Some(other) => {
+                errors.push(format!("Unknown log format {}, use one of {}", other, LOG_FORMATS.join(", ")));
+                LogFormat::Text
+            }
+        };
+
+        let http_listen = var("HTTP_LISTEN").or(file.http.listen).filter(|value| !value.is_empty())
+            .and_then(|value| match value.parse::<SocketAddr>() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    errors.push(format!("HTTP_LISTEN: {} is not an address and port, such as 127.0.0.1:9100", value));
+                    None
+                }
+            });
+
+        match path {
+            Some(path) if errors.is_empty() => Ok(Config {
+                token,
+                storage: StorageConfig { path, auto_create, legacy_json, backups, backup_interval, backup_dir },
+                guilds,
+                prefix,
+                delimiters,
+                mod_roles,
+                deny: DenyList { roles: deny_roles, permissions: deny_permissions },
+                intents,
+                reconcile_interval,
+                logging: LoggingConfig { level: log_level, format: log_format },
+                http_listen,
+                source: None,
+            }),
+            _ => Err(ConfigError(errors)),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "1" | "yes" => Some(true),
+        "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// A number from the environment variable `name`, if set, or else the file.
+fn number(errors: &mut Vec<String>, name: &str, value: Option<String>, file: Option<u64>, default: u64) -> u64 {
+    match value {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            errors.push(format!("{} must be a whole number, not {}", name, value));
+            default
+        }),
+        None => file.unwrap_or(default),
+    }
+}
+
+/// A period of `amount` units of `unit` seconds, `None` for 0. `amount` may
+/// be at most `max`, which also keeps it from overflowing.
+fn period(errors: &mut Vec<String>, name: &str, amount: u64, unit: u64, max: u64) -> Option<Duration> {
+    match amount.checked_mul(unit) {
+        Some(0) => None,
+        Some(secs) if amount <= max => Some(Duration::from_secs(secs)),
+        _ => {
+            errors.push(format!("{} must be at most {}, not {}", name, max, amount));
+            None
+        }
+    }
+}
+
+/// Split a comma-separated environment variable.
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+fn intent(name: &str) -> Option<GatewayIntents> {
+    Some(match name {
+        "all" => GatewayIntents::all(),
+        "non_privileged" => GatewayIntents::non_privileged(),
+        "guilds" => GatewayIntents::GUILDS,
+        "guild_members" => GatewayIntents::GUILD_MEMBERS,
+        "guild_bans" => GatewayIntents::GUILD_BANS,
+        "guild_emojis_and_stickers" => GatewayIntents::GUILD_EMOJIS_AND_STICKERS,
+        "guild_integrations" => GatewayIntents::GUILD_INTEGRATIONS,
+        "guild_webhooks" => GatewayIntents::GUILD_WEBHOOKS,
+        "guild_invites" => GatewayIntents::GUILD_INVITES,
+        "guild_voice_states" => GatewayIntents::GUILD_VOICE_STATES,
+        "guild_presences" => GatewayIntents::GUILD_PRESENCES,
+        "guild_messages" => GatewayIntents::GUILD_MESSAGES,
+        "guild_message_reactions" => GatewayIntents::GUILD_MESSAGE_REACTIONS,
+        "guild_message_typing" => GatewayIntents::GUILD_MESSAGE_TYPING,
+        "direct_messages" => GatewayIntents::DIRECT_MESSAGES,
+        "direct_message_reactions" => GatewayIntents::DIRECT_MESSAGE_REACTIONS,
+        "direct_message_typing" => GatewayIntents::DIRECT_MESSAGE_TYPING,
+        "message_content" => GatewayIntents::MESSAGE_CONTENT,
+        "guild_scheduled_events" => GatewayIntents::GUILD_SCHEDULED_EVENTS,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolve the config file `toml` with the environment `env`.
+    fn resolve(toml: &str, env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let file = toml::from_str(toml).unwrap_or_else(|why| panic!("{}", why));
+        let var = |name: &str| env.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string());
+        Config::resolve(file, var, true, Vec::new())
+    }
+
+    fn config(toml: &str, env: &[(&str, &str)]) -> Config {
+        resolve(toml, env).unwrap_or_else(|why| panic!("{}", why))
+    }
+
+    fn errors(toml: &str, env: &[(&str, &str)]) -> Vec<String> {
+        match resolve(toml, env) {
+            Ok(_) => panic!("Expected the configuration to be refused"),
+            Err(ConfigError(errors)) => errors,
+        }
+    }
+
+    const MINIMAL: &str = "token = \"secret\"\n[storage]\npath = \"invites.db\"\n";
+
+    #[test]
+    fn defaults_fill_in_the_rest() {
+        let config = config(MINIMAL, &[]);
+        assert_eq!(config.prefix, "!");
+        assert_eq!(config.mod_roles, vec!["Mod"]);
+        assert_eq!(config.reconcile_interval, Some(Duration::from_secs(30 * 60)));
+        assert_eq!(config.storage.backup_interval, Some(Duration::from_secs(24 * 60 * 60)));
+        assert!(config.guilds.is_empty());
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let toml = "token = \"file\"\nprefix = \"?\"\nguilds = [1]\nmod_roles = [\"Staff\"]\n[storage]\npath = \"file.db\"\n";
+        let config = config(toml, &[
+            ("DISCORD_TOKEN", "env"),
+            ("DB_PATH", "env.db"),
+            ("COMMAND_PREFIX", "$"),
+            ("GUILD_IDS", "2, 3"),
+            ("MOD_ROLES", "Admin,Helper"),
+        ]);
+        assert_eq!(config.token, "env");
+        assert_eq!(config.storage.path, PathBuf::from("env.db"));
+        assert_eq!(config.prefix, "$");
+        assert_eq!(config.guilds, vec![2, 3]);
+        assert_eq!(config.mod_roles, vec!["Admin", "Helper"]);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let errors = errors("prefix = \"? \"\n[logging]\nlevel = \"loud\"\n", &[("GUILD_IDS", "12, x"), ("DB_AUTO_CREATE", "maybe")]);
+        assert_eq!(errors.len(), 6, "{:?}", errors);
+        for expected in ["No Discord token", "No invite store", "DB_AUTO_CREATE", "GUILD_IDS: x", "command prefix", "Unknown log level loud"] {
+            assert!(errors.iter().any(|why| why.contains(expected)), "{} not in {:?}", expected, errors);
+        }
+    }
+
+    #[test]
+    fn prefix_must_be_non_empty_without_spaces() {
+        for prefix in ["", " ", "! "] {
+            let errors = errors(MINIMAL, &[("COMMAND_PREFIX", prefix)]);
+            assert!(errors[0].starts_with("The command prefix"), "{:?}", errors);
+        }
+    }
+
+    #[test]
+    fn missing_mod_roles_name_a_usable_prefix() {
+        let errors = errors(MINIMAL, &[("COMMAND_PREFIX", ""), ("MOD_ROLES", "")]);
+        assert!(errors.iter().any(|why| why.ends_with("use the !invite commands")), "{:?}", errors);
+    }
+
+    #[test]
+    fn intents_must_be_known_and_include_the_required_ones() {
+        let errors = errors(MINIMAL, &[("INTENTS", "guilds, bogus")]);
+        assert_eq!(errors[0], "Unknown intent bogus");
+        assert!(errors[1].starts_with("The bot needs the guild_members, guild_invites"), "{:?}", errors);
+        assert!(config(MINIMAL, &[("INTENTS", "all")]).intents.is_all());
+    }
+
+    #[test]
+    fn periods_are_bounded() {
+        let max = MAX_RECONCILE_MINUTES.to_string();
+        assert_eq!(config(MINIMAL, &[("RECONCILE_MINUTES", &max)]).reconcile_interval, Some(Duration::from_secs(MAX_RECONCILE_MINUTES * 60)));
+        assert_eq!(config(MINIMAL, &[("RECONCILE_MINUTES", "0")]).reconcile_interval, None);
+        let errors = errors(MINIMAL, &[("RECONCILE_MINUTES", "18446744073709551615"), ("DB_BACKUP_HOURS", &(MAX_BACKUP_HOURS + 1).to_string())]);
+        assert_eq!(errors, vec![
+            format!("DB_BACKUP_HOURS must be at most {}, not {}", MAX_BACKUP_HOURS, MAX_BACKUP_HOURS + 1),
+            format!("RECONCILE_MINUTES must be at most {}, not {}", MAX_RECONCILE_MINUTES, u64::MAX),
+        ]);
+    }
+
+    #[test]
+    fn misspelled_keys_are_refused() {
+        assert!(toml::from_str::<ConfigFile>("tokn = \"secret\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("[storage]\npth = \"invites.db\"").is_err());
+        assert!(toml::from_str::<ConfigFile>(MINIMAL).is_ok());
+    }
+}
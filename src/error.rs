@@ -0,0 +1,109 @@
+/* The crate-wide error type, and retrying of calls that fail for a moment.
+ * Event handlers log these and carry on instead of panicking: a panic only
+ * takes down the task handling that one event, but leaves whatever it was
+ * doing half done. */
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::config::ConfigError;
+use crate::store::StoreError;
+
+#[derive(Debug)]
+pub enum Error {
+    Discord(serenity::Error),
+    Store(StoreError),
+    Config(ConfigError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the call may well succeed if it is simply tried again: server
+    /// errors, rate limits and connection problems. Anything else, such as
+    /// missing permissions, will fail the same way every time.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Discord(serenity::Error::Http(why)) => match why.as_ref() {
+                serenity::http::HttpError::UnsuccessfulRequest(response) => {
+                    let status = response.status_code.as_u16();
+                    status >= 500 || status == 429
+                }
+                serenity::http::HttpError::Request(_) => true,
+                _ => false,
+            },
+            Error::Discord(serenity::Error::Io(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Discord(why) => write!(f, "Discord error: {}", why),
+            Error::Store(why) => write!(f, "invite store error: {}", why),
+            Error::Config(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serenity::Error> for Error {
+    fn from(why: serenity::Error) -> Self {
+        Error::Discord(why)
+    }
+}
+
+impl From<StoreError> for Error {
+    fn from(why: StoreError) -> Self {
+        Error::Store(why)
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(why: ConfigError) -> Self {
+        Error::Config(why)
+    }
+}
+
+/// How often, and how patiently, to retry a call that failed for a moment.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub attempts: u32,
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Backoff {
+    /// For event handlers: a few quick attempts, as others may be waiting.
+    pub const HANDLER: Backoff = Backoff { attempts: 3, delay: Duration::from_secs(1), max_delay: Duration::from_secs(4) };
+    /// For startup, which has nothing better to do than wait.
+    pub const STARTUP: Backoff = Backoff { attempts: 6, delay: Duration::from_secs(2), max_delay: Duration::from_secs(60) };
+
+    /// Run `call` until it succeeds, fails with an error that is not
+    /// transient, or runs out of attempts, doubling the delay every time.
+    pub async fn retry<T, E, Fut>(&self, what: &str, mut call: impl FnMut() -> Fut) -> Result<T>
+    where
+        E: Into<Error>,
+        Fut: Future<Output = std::result::Result<T, E>>,
+    {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match call().await.map_err(Into::into) {
+                Ok(value) => return Ok(value),
+                Err(why) if why.is_transient() && attempt < self.attempts => {
+                    warn!(attempt, attempts = self.attempts, ?delay, error = %why, "{} failed, retrying", what);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                Err(why) => return Err(why),
+            }
+        }
+    }
+}
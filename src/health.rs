@@ -0,0 +1,116 @@
+/* What the bot knows about its own health, for the /healthz and /readyz
+ * endpoints. The event handlers keep it up to date; the endpoints only read
+ * it. */
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// How long the gateway may be down before the bot counts as unhealthy.
+/// Serenity reconnects by itself, which normally takes seconds.
+pub const DISCONNECT_GRACE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub struct Health {
+    started: Instant,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    connected: bool,
+    /// When `connected` last changed, or when the bot started.
+    since: Instant,
+    last_event: Option<i64>,
+    reconciled: bool,
+    shutting_down: bool,
+}
+
+/// The state reported by both endpoints.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Whether the process is working, or should be restarted.
+    pub live: bool,
+    /// Whether the bot is attributing joins as it should.
+    pub ready: bool,
+    pub gateway: &'static str,
+    /// Seconds since the gateway connected or disconnected.
+    pub gateway_since: u64,
+    /// When the last event arrived from Discord, as a Unix timestamp.
+    pub last_event: Option<i64>,
+    pub storage: &'static str,
+    /// Whether the invites of every guild were reconciled since the gateway
+    /// last connected.
+    pub reconciled: bool,
+    pub shutting_down: bool,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            started: Instant::now(),
+            state: Mutex::new(State {
+                connected: false,
+                since: Instant::now(),
+                last_event: None,
+                reconciled: false,
+                shutting_down: false,
+            }),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_connected(&self, connected: bool) {
+        let mut state = self.state();
+        if state.connected != connected {
+            state.connected = connected;
+            state.since = Instant::now();
+        }
+    }
+
+    /// Note that an event arrived from Discord.
+    pub fn event(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        self.state().last_event = Some(now);
+    }
+
+    /// Note whether the invites of every guild have been reconciled since
+    /// the gateway last connected.
+    pub fn set_reconciled(&self, reconciled: bool) {
+        self.state().reconciled = reconciled;
+    }
+
+    /// Note that the bot is shutting down, so it no longer counts as ready.
+    pub fn set_shutting_down(&self) {
+        self.state().shutting_down = true;
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state().shutting_down
+    }
+
+    /// How long the bot has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn report(&self, storage_ok: bool) -> Report {
+        let state = self.state();
+        let since = state.since.elapsed();
+        Report {
+            live: state.connected || since < DISCONNECT_GRACE,
+            ready: state.connected && storage_ok && state.reconciled && !state.shutting_down,
+            gateway: if state.connected { "connected" } else { "disconnected" },
+            gateway_since: since.as_secs(),
+            last_event: state.last_event,
+            storage: if storage_ok { "ok" } else { "unavailable" },
+            reconciled: state.reconciled,
+            shutting_down: state.shutting_down,
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Health lock poisoned")
+    }
+}
@@ -0,0 +1,123 @@
+/* A small HTTP server for metrics and health probes, listening on
+ * `http.listen` if set. It only answers GET requests for a handful of fixed
+ * paths, so it ignores everything but the request line and closes the
+ * connection after every response. Bind it to localhost or a private network:
+ * nothing here asks who is connecting. */
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::store::MappingStore;
+
+// Requests taking longer than this to arrive are dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// What the endpoints report on.
+pub struct Monitor {
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<Health>,
+    pub store: Arc<dyn MappingStore>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Response { status, content_type: "text/plain; charset=utf-8", body: body.into() }
+    }
+}
+
+/// Answer requests on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, monitor: Arc<Monitor>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let monitor = monitor.clone();
+                tokio::spawn(async move {
+                    if let Err(why) = handle(stream, &monitor).await {
+                        debug!(%peer, error = %why, "Error answering HTTP request");
+                    }
+                });
+            }
+            Err(why) => {
+                warn!(error = %why, "Error accepting HTTP connection");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, monitor: &Monitor) -> io::Result<()> {
+    let request_line = match tokio::time::timeout(READ_TIMEOUT, read_request_head(&mut stream)).await {
+        Ok(line) => line?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long")),
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => route(target.split('?').next().unwrap_or(target), monitor),
+        (Some(_), Some(_)) => Response::text("405 Method Not Allowed", "Only GET is supported\n"),
+        _ => Response::text("400 Bad Request", "Bad request\n"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, response.content_type, response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn route(path: &str, monitor: &Monitor) -> Response {
+    match path {
+        "/metrics" => {
+            let invites = monitor.store.list().map(|invites| invites.len()).ok();
+            Response {
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4; charset=utf-8",
+                body: monitor.metrics.render(invites),
+            }
+        }
+        "/healthz" | "/readyz" => {
+            let report = monitor.health.report(monitor.store.list().is_ok());
+            let ok = if path == "/healthz" { report.live } else { report.ready };
+            Response {
+                status: if ok { "200 OK" } else { "503 Service Unavailable" },
+                content_type: "application/json",
+                body: serde_json::to_string(&report).expect("Health report is serializable") + "\n",
+            }
+        }
+        _ => Response::text("404 Not Found", "Not found\n"),
+    }
+}
+
+/// Read the request up to the blank line ending its headers, and return its
+/// first line. Reading the headers too means the client is not cut off while
+/// still sending them.
+async fn read_request_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") || buf.windows(2).any(|w| w == b"\n\n") {
+            let line = buf.split(|&b| b == b'\n').next().unwrap_or_default();
+            return Ok(String::from_utf8_lossy(line).trim_end().to_string());
+        }
+        if n == 0 || buf.len() > MAX_REQUEST {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete request"));
+        }
+    }
+}
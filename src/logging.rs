@@ -0,0 +1,41 @@
+/* Logging through `tracing`. Event handlers and commands run in spans naming
+ * the guild, user, invite and command they are about, so every line logged
+ * while handling them can be traced back to what caused it. Never log the
+ * token or the config as a whole. */
+use serenity::async_trait;
+use serenity::client::Context;
+use serenity::framework::{Framework, StandardFramework};
+use serenity::model::channel::Message;
+use tracing::{field, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogFormat, LoggingConfig};
+
+/// Install the global logger. `RUST_LOG`, if set, replaces the configured
+/// level, e.g. to debug serenity itself.
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("warn,{}={}", env!("CARGO_CRATE_NAME"), config.level)));
+    let logger = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Text => logger.init(),
+        LogFormat::Pretty => logger.pretty().init(),
+        LogFormat::Json => logger.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+/// The standard framework, run inside a span for every message so commands
+/// are logged along with who ran them and where. The command name is filled
+/// in by the `before` hook, once the framework has parsed it.
+pub struct TracedFramework(pub StandardFramework);
+
+#[async_trait]
+impl Framework for TracedFramework {
+    async fn dispatch(&self, ctx: Context, msg: Message) {
+        let span = info_span!("message", guild = field::Empty, channel = %msg.channel_id, user = %msg.author.id, command = field::Empty);
+        if let Some(guild_id) = msg.guild_id {
+            span.record("guild", guild_id.0);
+        }
+        self.0.dispatch(ctx, msg).instrument(span).await
+    }
+}
@@ -3,13 +3,31 @@
  * for providing the initial structure of this bot.
  * Especially the serenity GitHub, your examples have been fantastic for learning. */
 
+mod attribution;
+mod cli;
 mod commands;
-
-use std::io::Write;
-use std::{env, fs};
-use std::collections::{HashSet, HashMap};
+mod config;
+mod error;
+mod health;
+mod http;
+mod logging;
+mod metrics;
+mod roles;
+mod shutdown;
+mod stats;
+mod store;
+
+use std::env;
+use std::collections::HashSet;
 use std::sync::Arc;
-use serenity::model::prelude::{GuildId, Member, Role, RoleId, InviteCreateEvent, ResumedEvent, InviteDeleteEvent};
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use serenity::model::prelude::{ChannelId, Guild, GuildId, Member, Message, RoleId, Timestamp, User, InviteCreateEvent, ResumedEvent, InviteDeleteEvent};
+use serenity::model::application::interaction::Interaction;
+use serenity::client::bridge::gateway::ShardManager;
+use serenity::client::bridge::gateway::event::ShardStageUpdateEvent;
+use serenity::gateway::ConnectionStage;
+use tokio::time::MissedTickBehavior;
 use serenity::{
     async_trait,
     model::gateway::Ready,
@@ -17,28 +35,79 @@ use serenity::{
 };
 use serenity::http::Http;
 use serenity::framework::StandardFramework;
-use serenity::framework::standard::macros::group;
-use serde::{Deserialize, Serialize};
+use serenity::framework::standard::{CommandResult, DispatchError, Reason};
+use serenity::framework::standard::buckets::LimitedFor;
+use serenity::framework::standard::macros::{group, hook};
+use tracing::{debug, error, info, instrument, warn};
 // use serenity::model::event::ResumedEvent;
 
 use crate::commands::*; // Update to crate::commands::filename::* when filename is no longer
                         // "mod.rs"
 use crate::commands::invite::*;
+use crate::commands::admin::*;
+use crate::attribution::{Attribution, GuildLocks};
+use crate::cli::{fatal, Options, EXIT_CONFIG, EXIT_IO_ERROR, EXIT_NO_STORE, EXIT_TEMPFAIL, EXIT_UNAVAILABLE};
+use crate::config::Config;
+use crate::error::Backoff;
+use crate::health::Health;
+use crate::logging::TracedFramework;
+use crate::metrics::Metrics;
+use crate::store::backup::Backups;
+use crate::store::{Confidence, Drift, GuildSettings, JoinFilter, JoinRecord, LiveInvite, MappingStore, StoreResult};
+
+// The `InviteTracker` holds the invite store: "<invite-id>: ([role ids], uses)".
+// Every change to a mapping is written to the store as it happens.
+struct InviteTracker;
+impl TypeMapKey for InviteTracker {
+    type Value = Arc<dyn MappingStore>;
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct InviteRoles {
-    code: String,
-    roles: Vec<Role>,
+// The configuration the bot was started with
+struct BotConfig;
+impl TypeMapKey for BotConfig {
+    type Value = Arc<Config>;
 }
 
+// Makes sure the joins of a guild are attributed one at a time, and holds the
+// joins that could not be attributed yet
+struct JoinLocks;
+impl TypeMapKey for JoinLocks {
+    type Value = Arc<GuildLocks<Vec<Member>>>;
+}
 
-// We want an `InviteTracker` object to look like: "<invite-id>: ([roles], uses)"
-struct InviteTracker;
-impl TypeMapKey for InviteTracker {
-    type Value = Arc<RwLock<HashMap<String, (Vec<Role>, u64)>>>;
+// The most members Discord lists at once
+const MEMBER_PAGE: u64 = 1000;
+
+// The most joins to queue per guild while its invites cannot be fetched. The
+// more there are, the less likely they can be told apart once they can be.
+const MAX_PENDING: usize = 50;
+
+// How long to wait for joins being attributed when shutting down
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(15);
+
+// Counts what the bot does, for the /metrics endpoint
+struct BotMetrics;
+impl TypeMapKey for BotMetrics {
+    type Value = Arc<Metrics>;
 }
 
-struct Handler;
+// The gateway and reconciliation state, for the /healthz and /readyz endpoints
+struct BotHealth;
+impl TypeMapKey for BotHealth {
+    type Value = Arc<Health>;
+}
+
+// Lets `!admin shutdown` disconnect every shard, the same way a signal does
+struct ShardManagerContainer;
+impl TypeMapKey for ShardManagerContainer {
+    type Value = Arc<Mutex<ShardManager>>;
+}
+
+struct Handler {
+    // Whether the periodic reconciliation is running, as `ready` is called
+    // again whenever the bot reconnects
+    reconciling: AtomicBool,
+}
 
 #[group] // Create a group of commands
 #[description = "A group of general commands"] // ...with this description
@@ -52,286 +121,798 @@ struct General; // The name of the command group
 #[summary = "Change link-roles associations"]
 #[prefixes("invite", "inv")]
 #[default_command("list")]
-#[commands("link", "unlink", "list", "sync", "create")]
-#[allowed_roles("Mod")]
+#[commands("link", "unlink", "list", "sync", "create", "config", "history", "stats", "label", "note")]
+#[only_in(guilds)]
+#[checks(Moderator)]
 struct Invite;
 
 #[group]
 #[owners_only]
-// Limit all commands to be guild-restricted.
-#[only_in(guilds)] // Guild = server. ID?
+#[description = "Look after the bot itself, across every guild it is in"]
 // Summary only appears when listing multiple groups.
-#[summary = "Commands for server admins"]
-#[commands(company)]
+#[summary = "Commands for the bot's owners"]
+#[prefixes("admin")]
+#[default_command(status)]
+#[commands(status, guilds, reconcile, reload, shutdown)]
 struct Owner;
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _: Context, ready: Ready){
-        println!("{} is connected!", ready.user.name);
+    #[instrument(skip_all)]
+    async fn ready(&self, ctx: Context, ready: Ready){
+        info!(user = %ready.user.tag(), guilds = ready.guilds.len(), "Connected");
+        let health = health(&ctx).await;
+        health.set_connected(true);
+        health.event();
+        let config = {
+            let data = ctx.data.read().await;
+            data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone()
+        };
+
+        for guild in ready.guilds.iter().filter(|g| config.serves(g.id.0)) {
+            register_commands(&ctx, guild.id).await;
+        }
+
+        // Reconcile the stored invites of every guild we are in, as we may
+        // have missed invites being created, used or deleted while offline.
+        // Catch up on members who joined meanwhile, too.
+        // Only ready once every guild is, or joins may go unattributed
+        health.set_reconciled(reconcile_guilds(&ctx, ready.guilds.iter().map(|g| g.id), true).await);
+
+        // And keep doing so every now and then, in case events go missing
+        // while connected too
+        if let Some(period) = config.reconcile_interval {
+            if !self.reconciling.swap(true, Ordering::SeqCst) {
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                    // The first tick completes right away, and we have just reconciled
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        debug!("Reconciling the invites of every guild");
+                        health.set_reconciled(reconcile_guilds(&ctx, ctx.cache.guilds(), false).await);
+                    }
+                });
+            }
+        }
+    }
+
+    // Every guild is in the cache now, so check that the roles linked to
+    // invites can still be given
+    async fn cache_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+        let config = {
+            let data = ctx.data.read().await;
+            data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone()
+        };
+        for guild_id in guilds.into_iter().filter(|id| config.serves(id.0)) {
+            preflight_guild(&ctx, guild_id).await;
+        }
+    }
+
+    #[instrument(skip_all, fields(guild = %guild.id))]
+    async fn guild_create(&self, ctx: Context, guild: Guild, is_new: bool) {
+        health(&ctx).await.event();
+        // Guilds we were already in are reconciled on ready
+        if is_new {
+            info!(name = %guild.name, "Joined guild");
+            register_commands(&ctx, guild.id).await;
+            reconcile_guilds(&ctx, [guild.id], false).await;
+        }
     }
 
     /// On guild member addition, we want to:
-    /// 1. Check which invite they have used by comparing our cached invite count
-    ///    to our server's invite count.
+    /// 1. Check which invite they have used by diffing the stored invite counts
+    ///    against our server's invite counts (see `attribution`).
     /// 2. Assign the new member all roles associated with the invite. Associations
-    ///    are based on the InviteTracker struct loaded at start and updated by the
+    ///    are based on the InviteTracker store loaded at start and updated by the
     ///    role association commands.
-    async fn guild_member_addition(&self, ctx: Context, mut newmem: Member) {
-        if let Ok(active_invites) = newmem.guild_id.invites(&ctx.http).await {
-            // We can assume that the same invite codes are present in both
-            // the cached invites and the ones we get from the API call, as the (TODO)
-            // invite_add and invite_delete events will update the cached ones
+    #[instrument(skip_all, fields(guild = %newmem.guild_id, user = %newmem.user.id))]
+    async fn guild_member_addition(&self, ctx: Context, newmem: Member) {
+        health(&ctx).await.event();
+        let (store, locks, config) = {
             let data = ctx.data.read().await;
-            let cached_invites = data.get::<InviteTracker>()
-                .expect("Could not find cached InviteTracker object");
-
-            for inv in active_invites {
-                if let Some( (rls, cached_count) ) = cached_invites.write().await.get_mut(&inv.code) {
-                    if inv.uses > *cached_count {
-                        println!("Invite changed: {}", inv.code);
-                        println!("Roles: {:?}", rls);
-                        // For some reason, we need to specify the type to collect into...
-                        let roleids = rls.iter().map(|r| r.id).collect::<Vec<RoleId>>();
-                        if let Err(why) = newmem.add_roles(&ctx.http, &roleids).await {
-                            println!("Error adding roles: {:?}", why);
-                        }
-
-                        // Also, update the cached_invites values
-                        println!("Updating cached invite use count. Current: {}", *cached_count);
-                        *cached_count = inv.uses;
-                        println!("New count: {}", *cached_count);
-                        break;
-                    }
-                }
+            (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+             data.get::<JoinLocks>().expect("Expected JoinLocks in data/typemap").clone(),
+             data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone())
+        };
+        let guild_id = newmem.guild_id;
+        if !config.serves(guild_id.0) {
+            return;
+        }
+        // Bots are added through OAuth, without using an invite
+        if newmem.user.bot {
+            debug!("Not attributing a bot");
+            return;
+        }
+
+        // Hold the guild's lock from fetching the invites until the new counts
+        // are stored, so concurrent joins cannot claim the same use. Joins we
+        // could not attribute before are queued behind the same lock; if the
+        // invites cannot be fetched now either, this one joins the queue.
+        let mut pending = locks.lock(guild_id.0).await;
+        pending.push(newmem);
+        let attributed = match fetch_live_invites(&ctx.http, guild_id).await {
+            Ok(live) => attribution::attribute_all(store.as_ref(), guild_id.0, &mut pending, &live),
+            Err(why) if why.is_transient() && pending.len() <= MAX_PENDING => {
+                warn!(pending = pending.len(), error = %why, "Could not get the guild's invites, queued the join to be attributed later");
+                Vec::new()
+            }
+            // Trying again will not help, e.g. as the bot lacks Manage Server,
+            // so record the queued joins as they are rather than letting
+            // them pile up
+            Err(why) if !why.is_transient() => {
+                warn!(pending = pending.len(), error = %why, "Could not get the guild's invites, recording the joins as unknown");
+                pending.drain(..).map(|member| (member, Ok(Attribution::Unknown))).collect()
             }
-        } else {
-            panic!("Error getting invites");
+            Err(why) => {
+                warn!(pending = pending.len(), error = %why, "Could not get the guild's invites and too many joins are queued, recording the join as unknown");
+                pending.pop().map(|member| (member, Ok(Attribution::Unknown))).into_iter().collect()
+            }
+        };
+
+        // Keep holding the lock until the roles are assigned and the joins are
+        // recorded, as shutting down waits for it: their uses are consumed
+        // already, so a join left unfinished could not be caught up on later
+        for (member, attribution) in attributed {
+            finish_join(&ctx, store.as_ref(), member, attribution).await;
         }
     }
 
+    #[instrument(skip_all, fields(guild = %guild_id, user = %user.id))]
+    async fn guild_member_removal(&self, ctx: Context, guild_id: GuildId, user: User, _: Option<Member>) {
+        health(&ctx).await.event();
+        // Note the leave in the join log, so `!invite stats` can tell who stayed
+        let (store, config) = {
+            let data = ctx.data.read().await;
+            (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+             data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone())
+        };
+        if !config.serves(guild_id.0) {
+            return;
+        }
+
+        if let Err(why) = store.record_leave(guild_id.0, user.id.0, Timestamp::now().unix_timestamp()) {
+            error!(error = %why, "Error recording the leave");
+        }
+    }
+
+    #[instrument(skip_all, fields(guild = ?inv_event.guild_id.map(|g| g.0), invite = %inv_event.code))]
     async fn invite_delete(&self, ctx: Context, inv_event: InviteDeleteEvent) {
-        // Add the invite to the hashmap without any roles linked to it
-        let data_locked = {
+        health(&ctx).await.event();
+        // Forget the invite along with any roles linked to it
+        let (store, config) = {
             let data = ctx.data.read().await;
-            data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+            (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+             data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone())
         };
+        if inv_event.guild_id.is_some_and(|guild_id| !config.serves(guild_id.0)) {
+            return;
+        }
 
-        {
-            let mut invites = data_locked.write().await;
-            invites.remove(&inv_event.code);
+        // Discord deletes invites that reach their max uses, possibly before we
+        // have handled the join that used them up. Leave those to the join
+        // handler, which needs the linked roles to attribute that join.
+        if let Some(guild_id) = inv_event.guild_id {
+            if let Ok(Some(inv)) = store.get(guild_id.0, &inv_event.code) {
+                if inv.max_uses > 0 && inv.uses + 1 >= inv.max_uses {
+                    return;
+                }
+            }
+        }
+
+        if let Err(why) = store.remove_invite(&inv_event.code) {
+            error!(error = %why, "Error removing invite from the store");
         }
     }
 
+    #[instrument(skip_all, fields(guild = ?inv_event.guild_id.map(|g| g.0), invite = %inv_event.code))]
     async fn invite_create(&self, ctx: Context, inv_event: InviteCreateEvent) {
-        // Add the invite to the hashmap without any roles linked to it
-        let data_locked = {
+        health(&ctx).await.event();
+        // Start tracking the invite without any roles linked to it
+        let (store, config) = {
+            let data = ctx.data.read().await;
+            (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+             data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone())
+        };
+
+        if let Some(guild_id) = inv_event.guild_id.filter(|guild_id| config.serves(guild_id.0)) {
+            let live = LiveInvite {
+                code: inv_event.code.clone(),
+                uses: 0,
+                max_uses: inv_event.max_uses,
+                inviter: inv_event.inviter.as_ref().map(|u| u.id.0),
+                created_at: inv_event.created_at.unix_timestamp(),
+            };
+            if let Err(why) = store.upsert_invite(guild_id.0, &live) {
+                error!(error = %why, "Error adding invite to the store");
+            }
+        }
+    }
+
+    // Commands are handled by the framework, this only notes the event
+    async fn message(&self, ctx: Context, _: Message) {
+        health(&ctx).await.event();
+    }
+
+    // Slash commands, and suggestions for the invite codes they take
+    #[instrument(skip_all, fields(guild = tracing::field::Empty, user = tracing::field::Empty, command = tracing::field::Empty))]
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let health = health(&ctx).await;
+        health.event();
+        let (config, metrics) = {
             let data = ctx.data.read().await;
-            data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone()
+            (data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone(),
+             data.get::<BotMetrics>().expect("Expected BotMetrics in data/typemap").clone())
         };
 
+        match interaction {
+            Interaction::ApplicationCommand(command) => {
+                if command.guild_id.is_some_and(|guild_id| !config.serves(guild_id.0)) {
+                    return;
+                }
+                let name = slash::name(&command);
+                let span = tracing::Span::current();
+                span.record("user", command.user.id.0).record("command", name);
+                if let Some(guild_id) = command.guild_id {
+                    span.record("guild", guild_id.0);
+                }
+
+                let result = if health.is_shutting_down() {
+                    slash::respond(&ctx, &command, "The bot is shutting down, try again in a moment.").await
+                } else {
+                    debug!("Running command");
+                    slash::run(&ctx, &command).await
+                };
+                if let Err(why) = &result {
+                    warn!(error = ?why, "Command failed");
+                }
+                metrics.command(name, result.is_ok());
+            }
+            Interaction::Autocomplete(autocomplete) => {
+                if autocomplete.guild_id.is_some_and(|guild_id| !config.serves(guild_id.0)) {
+                    return;
+                }
+                if let Err(why) = slash::autocomplete(&ctx, &autocomplete).await {
+                    warn!(error = ?why, "Error sending suggestions");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn resume(&self, ctx: Context, _: ResumedEvent) {
+        info!("Resumed");
+        let health = health(&ctx).await;
+        health.set_connected(true);
+        health.event();
         {
-            let mut invites = data_locked.write().await;
-            invites.entry(inv_event.code).or_insert((Vec::<Role>::new(), 0));
+            let data = ctx.data.read().await;
+            data.get::<BotMetrics>().expect("Expected BotMetrics in data/typemap").gateway_resumes.inc();
         }
+
+        // Events missed while disconnected are not replayed on resuming
+        health.set_reconciled(reconcile_guilds(&ctx, ctx.cache.guilds(), false).await);
     }
 
-    async fn resume(&self, _: Context, _: ResumedEvent) {
-        println!("Resumed");
+    async fn shard_stage_update(&self, ctx: Context, event: ShardStageUpdateEvent) {
+        debug!(shard = event.shard_id.0, from = %event.old, to = %event.new, "Gateway connection changed");
+        let health = health(&ctx).await;
+        let connected = event.new == ConnectionStage::Connected;
+        health.set_connected(connected);
+        // Invites may change while disconnected, so they are not reconciled
+        // until `ready` or `resume` has done so again
+        if !connected {
+            health.set_reconciled(false);
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    // This will load the environment variables located at `./.env`, relative to
-    // the CWD. See `./.env.example` for an example on how to structure this.
-    dotenv::dotenv().expect("Failed to load .env file");
-
-    let token = env::var("DISCORD_TOKEN")
-        .expect("Failed to get Discord token from environment.");
-    println!("Got token");
-
-    let http = Http::new(&token);
-
-    // Get the bot's owners + the bot's id.
-    let (owners, bot_id) = match http.get_current_application_info().await {
-        Ok(info) => {
-            println!("\nCurrent application info:\n{:?}\n", info);
-            let mut owners = HashSet::new();
-            if let Some(team) = info.team {
-                owners.insert(team.owner_user_id);
-            } else {
-                owners.insert(info.owner.id);
-            }
+async fn health(ctx: &Context) -> Arc<Health> {
+    let data = ctx.data.read().await;
+    data.get::<BotHealth>().expect("Expected BotHealth in data/typemap").clone()
+}
+
+/// Register the slash commands in a guild. The `!` commands keep working if
+/// this fails, e.g. as the bot was invited without the `applications.commands`
+/// scope.
+async fn register_commands(ctx: &Context, guild_id: GuildId) {
+    if let Err(why) = slash::register(ctx, guild_id).await {
+        warn!(guild = %guild_id, error = ?why, "Could not register slash commands");
+    }
+}
+
+/// Warn about roles linked to the guild's invites that the bot can no longer
+/// give, e.g. as they were moved above its own role or it lost Manage Roles.
+#[instrument(skip_all, fields(guild = %guild_id))]
+async fn preflight_guild(ctx: &Context, guild_id: GuildId) {
+    let (store, prefix) = {
+        let data = ctx.data.read().await;
+        (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+         data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").prefix.clone())
+    };
+    let invites = match store.list_guild(guild_id.0) {
+        Ok(invites) => invites,
+        Err(why) => {
+            error!(error = %why, "Could not read the invite store");
+            return;
+        }
+    };
 
-            match http.get_current_user().await {
-                Ok(bot_id) => (owners, bot_id.id),
-                Err(why) => panic!("Could not access the bot ID: {:?}", why),
+    for inv in invites.iter().filter(|inv| !inv.roles.is_empty()) {
+        match check_roles(ctx, guild_id, &inv.roles).await {
+            Some(checked) => {
+                for (_, name, grantable) in checked {
+                    if let Err(why) = grantable {
+                        warn!(invite = %inv.code, role = %name, reason = %why, "Linked role cannot be given to members, unlink it with {}invite unlink", prefix);
+                    }
+                }
+            }
+            None => {
+                warn!("Could not check whether the bot can give the linked roles");
+                return;
             }
-        },
-        Err(why) => panic!("Could not access the application info: {:?}", why)
+        }
+    }
+}
+
+/// Act on the attribution of a join: assign the linked roles, record the
+/// join in the guild's log and report it in the guild's log channel.
+#[instrument(skip_all, fields(guild = %member.guild_id, user = %member.user.id))]
+async fn finish_join(ctx: &Context, store: &dyn MappingStore, mut member: Member, attribution: StoreResult<Attribution>) {
+    let metrics = {
+        let data = ctx.data.read().await;
+        data.get::<BotMetrics>().expect("Expected BotMetrics in data/typemap").clone()
+    };
+    let settings = match store.guild_settings(member.guild_id.0) {
+        Ok(settings) => settings,
+        Err(why) => {
+            error!(error = %why, "Error reading guild settings");
+            GuildSettings::default()
+        }
     };
 
+    // Everything we learn about the join goes into the guild's join log
+    let mut join = JoinRecord {
+        guild_id: member.guild_id.0,
+        user_id: member.user.id.0,
+        joined_at: member.joined_at.unwrap_or_else(Timestamp::now).unix_timestamp(),
+        invite: None,
+        inviter: None,
+        roles: Vec::new(),
+        confidence: attribution.as_ref().map_or(Confidence::Unknown, |a| a.confidence()),
+        left_at: None,
+    };
 
-    let framework = StandardFramework::new()
-        .configure(|c| c
-                   .with_whitespace(true)
-                   .on_mention(Some(bot_id))
-                   .prefix("!")
-                   .delimiters(vec![", ", ",", " "])
-                   .owners(owners))
-        .group(&GENERAL_GROUP)
-        .group(&INVITE_GROUP);
-    /* TODO: Read up on the functionality below and configure it after proper understanding to
-     * avoid copy pasting too much.
-    // Set a function to be called prior to each command execution. This
-    // provides the context of the command, the message that was received,
-    // and the full name of the command that will be called.
-    //
-    // Avoid using this to determine whether a specific command should be
-    // executed. Instead, prefer using the `#[check]` macro which
-    // gives you this functionality.
-    //
-    // **Note**: Async closures are unstable, you may use them in your
-    // application if you are fine using nightly Rust.
-    // If not, we need to provide the function identifiers to the
-    // hook-functions (before, after, normal, ...).
-    .before(before)
-    // Similar to `before`, except will be called directly _after_
-    // command execution.
-    .after(after)
-    // Set a function that's called whenever an attempted command-call's
-    // command could not be found.
-    .unrecognised_command(unknown_command)
-    // Set a function that's called whenever a message is not a command.
-    .normal_message(normal_message)
-    // Set a function that's called whenever a command's execution didn't complete for one
-    // reason or another. For example, when a user has exceeded a rate-limit or a command
-    // can only be performed by the bot owner.
-    .on_dispatch_error(dispatch_error)
-    // Can't be used more than once per 5 seconds:
-    .bucket("emoji", |b| b.delay(5)).await
-    // Can't be used more than 2 times per 30 seconds, with a 5 second delay applying per channel.
-    // Optionally `await_ratelimits` will delay until the command can be executed instead of
-    // cancelling the command invocation.
-    .bucket("complicated", |b| b.limit(2).time_span(30).delay(5)
-    // The target each bucket will apply to.
-    .limit_for(LimitedFor::Channel)
-    // The maximum amount of command invocations that can be delayed per target.
-    // Setting this to 0 (default) will never await/delay commands and cancel the invocation.
-    .await_ratelimits(1)
-    // A function to call when a rate limit leads to a delay.
-    .delay_action(delay_action)).await
-    // The `#[group]` macro generates `static` instances of the options set for the group.
-    // They're made in the pattern: `#name_GROUP` for the group instance and `#name_GROUP_OPTIONS`.
-    // #name is turned all uppercase
-    .help(&MY_HELP)
-    .group(&GENERAL_GROUP)
-    .group(&EMOJI_GROUP)
-    .group(&MATH_GROUP)
-    .group(&OWNER_GROUP)
-    */
-
-    // Give bot access to all channels for reading etc
-    let intents = GatewayIntents::all();
-    let mut client = Client::builder(token, intents)
-        .event_handler(Handler)
-        .framework(framework)
-        .await
-        .expect("Error creating client");
-    println!("Built client");
-
-    // Construct a hash map to be contained in an InviteTracker object
-    // where it will be wrapped in Arc<RwLock> for thread safety.
-    // Used to track invites' associated roles and auto-assign them on join
-    let db_path = env::var("JSON_PATH")
-        .expect("Could not find the JSON_PATH variable in environment");
-    let db_string = {
-        match fs::read_to_string(&db_path) {
-            Ok(contents) => contents,
-            Err(why) => {
-                // Create file and return empty string
-                println!("Could not read db file due to the following error: {:?}.", why);
-                let mut ans = String::new();
-                loop {
-                    println!("Do you want to create the file? y/n");
-                    std::io::stdin()
-                        .read_line(&mut ans)
-                        .expect("Failed to read input");
-                    match ans.to_lowercase().trim() {
-                        "y" => {
-                            // Create file
-                            {
-                                let mut f = fs::File::create(&db_path)
-                                    .expect("Failed to create DB file");
-                                f.write_all(b"[]")
-                                    .expect("Failed to write to file");
-                            }
-                            break;
-                        }, 
-                        "n" => break,
-                        _ => continue
+    let report = match attribution {
+        Ok(Attribution::Invite { invite: inv, confidence }) => {
+            info!(invite = %inv.code, uses = inv.uses, confidence = confidence.as_str(), "Attributed join");
+            metrics.joins_attributed.inc();
+            // Resolve the linked roles live, skipping any that have been deleted
+            let guild_roles = ctx.cache.guild_field(member.guild_id, |g| g.roles.clone()).unwrap_or_default();
+            let (mut roles, deleted) = resolve_roles(&guild_roles, &inv.roles);
+            for id in deleted {
+                warn!(invite = %inv.code, role = id, "Linked role no longer exists in the guild");
+            }
+            // Discord refuses the whole lot if the bot cannot give one of them
+            if let Some(checked) = check_roles(ctx, member.guild_id, &inv.roles).await {
+                for (id, name, grantable) in checked {
+                    if let Err(why) = grantable {
+                        warn!(invite = %inv.code, role = %name, reason = %why, "Not assigning linked role the bot cannot give");
+                        roles.retain(|role| role.id.0 != id);
                     }
                 }
+            }
+            if settings.auto_assign {
+                debug!(roles = ?roles.iter().map(|r| &r.name).collect::<Vec<_>>(), "Assigning roles");
+                let roleids = roles.iter().map(|r| r.id).collect::<Vec<RoleId>>();
+                match member.add_roles(&ctx.http, &roleids).await {
+                    Ok(_) => {
+                        metrics.roles_assigned.add(roleids.len() as u64);
+                        join.roles = roleids.iter().map(|r| r.0).collect();
+                    }
+                    Err(why) => {
+                        metrics.roles_failed.add(roleids.len() as u64);
+                        error!(invite = %inv.code, error = ?why, "Error adding roles");
+                    }
+                }
+            }
+            join.invite = Some(inv.code.clone());
+            join.inviter = inv.inviter;
+            format!("{} joined through invite {}", member.user.tag(), inv.code)
+        }
+        Ok(Attribution::Ambiguous { candidates }) => {
+            info!(?candidates, "Could not tell which invite was used");
+            metrics.joins_ambiguous.inc();
+            format!("{} joined through one of {}, no roles were assigned", member.user.tag(), candidates.join(", "))
+        }
+        Ok(Attribution::Unknown) => {
+            info!("Could not find the invite used");
+            metrics.joins_unknown.inc();
+            format!("{} joined through an unknown invite", member.user.tag())
+        }
+        Err(why) => {
+            error!(error = %why, "Error reading stored invites");
+            metrics.joins_failed.inc();
+            format!("{} joined, but the invite store could not be read", member.user.tag())
+        }
+    };
+
+    if let Err(why) = store.record_join(&join) {
+        error!(error = %why, "Error recording the join");
+    }
+
+    if let Some(channel) = settings.log_channel {
+        if let Err(why) = ChannelId(channel).say(&ctx.http, report).await {
+            warn!(error = ?why, "Error sending message");
+        }
+    }
+}
+
+/// The guild's invites, retrying for a bit if Discord does not answer.
+async fn fetch_live_invites(http: &Http, guild_id: GuildId) -> error::Result<Vec<LiveInvite>> {
+    let invites = Backoff::HANDLER.retry(&format!("Getting the invites of guild {}", guild_id), || guild_id.invites(http)).await?;
+    Ok(live_invites(&invites))
+}
+
+/// Members of the guild who joined after `since` but are missing from its join
+/// log, oldest first. Bots are left out, as they are added without an invite.
+async fn missed_joins(http: &Http, store: &dyn MappingStore, guild_id: GuildId, since: i64) -> error::Result<Vec<Member>> {
+    let recorded = store.joins(guild_id.0, &JoinFilter::All)?
+        .into_iter()
+        .filter(|join| join.joined_at >= since)
+        .map(|join| (join.user_id, join.joined_at))
+        .collect::<HashSet<_>>();
+
+    // Members are listed by ID, not by when they joined, so go through all
+    let mut missed = Vec::new();
+    let mut after = None;
+    loop {
+        let page = Backoff::HANDLER.retry(
+            &format!("Getting the members of guild {}", guild_id),
+            || guild_id.members(http, Some(MEMBER_PAGE), after),
+        ).await?;
+        let last_page = (page.len() as u64) < MEMBER_PAGE;
+        after = page.last().map(|member| member.user.id);
+        missed.extend(page.into_iter().filter(|member| {
+            let joined_at = member.joined_at.map_or(0, |t| t.unix_timestamp());
+            !member.user.bot && joined_at > since && !recorded.contains(&(member.user.id.0, joined_at))
+        }));
+        if last_page {
+            break;
+        }
+    }
+    missed.sort_by_key(|member| member.joined_at);
+    Ok(missed)
+}
+
+/// Reconcile every guild in `guild_ids` the bot serves, one at a time,
+/// logging those that fail. Returns whether every guild was reconciled. See
+/// `reconcile_guild` for `catch_up`.
+async fn reconcile_guilds(ctx: &Context, guild_ids: impl IntoIterator<Item = GuildId>, catch_up: bool) -> bool {
+    let config = {
+        let data = ctx.data.read().await;
+        data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").clone()
+    };
+    let mut reconciled = true;
+    for guild_id in guild_ids.into_iter().filter(|id| config.serves(id.0)) {
+        if let Err(why) = reconcile_guild(ctx, guild_id, catch_up).await {
+            error!(guild = %guild_id, error = %why, "Could not reconcile invites");
+            reconciled = false;
+        }
+    }
+    reconciled
+}
 
-                "[]".into()
+/// Get the guild's invites from the Discord API, drop stored invites that no
+/// longer exist in the guild, track those we missed being created and refresh
+/// the use counts of the others. Joins waiting to be attributed are attributed
+/// first, as refreshing the counts would erase the uses they are attributed by.
+/// With `catch_up`, members who joined since the counts were last reconciled
+/// but never made it into the join log, as the bot was offline, are
+/// attributed too (see `attribution::attribute_missed`). Also reports
+/// mappings to roles that have been deleted since they were linked. Returns
+/// what had drifted.
+#[instrument(skip_all, fields(guild = %guild_id))]
+async fn reconcile_guild(ctx: &Context, guild_id: GuildId, catch_up: bool) -> error::Result<Drift> {
+    let (store, locks, prefix) = {
+        let data = ctx.data.read().await;
+        (data.get::<InviteTracker>().expect("Expected InviteTracker in data/typemap").clone(),
+         data.get::<JoinLocks>().expect("Expected JoinLocks in data/typemap").clone(),
+         data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").prefix.clone())
+    };
+    let counted_at = if catch_up { store.counted_at(guild_id.0)? } else { None };
+
+    let drift = {
+        let mut pending = locks.lock(guild_id.0).await;
+        let now = Timestamp::now().unix_timestamp();
+        let live = fetch_live_invites(&ctx.http, guild_id).await?;
+
+        // Queued joins would claim the same uses as the missed ones, and
+        // cannot be told apart from them, so only catch up without any
+        let missed = match counted_at {
+            Some(since) if pending.is_empty() => match missed_joins(&ctx.http, store.as_ref(), guild_id, since).await {
+                Ok(members) => attribution::attribute_missed(store.as_ref(), guild_id.0, members, &live)?,
+                Err(why) => {
+                    error!(error = %why, "Could not look for members who joined while offline");
+                    Vec::new()
+                }
+            },
+            Some(_) => {
+                debug!(pending = pending.len(), "Not looking for joins missed while offline, as joins are queued");
+                Vec::new()
             }
+            None => Vec::new(),
+        };
+        let attributed = attribution::attribute_all(store.as_ref(), guild_id.0, &mut pending, &live);
+        let drift = store::reconcile(store.as_ref(), guild_id.0, &live)
+            .and_then(|drift| store.set_counted_at(guild_id.0, now).map(|_| drift));
+
+        // Finish the joins before letting go of the lock, so shutting down
+        // waits for them (see `guild_member_addition`)
+        if !missed.is_empty() {
+            info!(count = missed.len(), "Catching up on members who joined while offline");
+        }
+        for (member, attribution) in missed {
+            finish_join(ctx, store.as_ref(), member, Ok(attribution)).await;
+        }
+        for (member, attribution) in attributed {
+            finish_join(ctx, store.as_ref(), member, attribution).await;
         }
+        drift
     };
-    println!("Db string read: {}", db_string);
-    let local_invite_mappings: Vec<InviteRoles> = serde_json::from_str(&db_string)
-        .expect("Error getting invite mappings");
-
-    // Get known invites from discord api
-    // for each of the local invites:
-    // check if the code (key) exists in active_invites
-    // if it doesn't, remove it from the json file
-    let mut cached_invite_map = HashMap::<String, (Vec<Role>, u64)>::default();
-
-    let guild_id = env::var("GUILD_ID")
-        .expect("Could not find the GUILD_ID variable in environment").parse().expect("Unable to parse numeric guild id.");
-
-    if let Ok(active_invites) = GuildId(guild_id).invites(http).await {
-        'new_local: for inv in local_invite_mappings {
-            for ac_inv in &active_invites {
-                if ac_inv.code == inv.code {
-                    // println!("Inv roles for {} are {:?}", inv.code, inv.roles);
-                    // active_invites contains invite from disk
-                    cached_invite_map
-                        .entry(inv.code.to_string())
-                        .or_insert((Vec::<Role>::new(), ac_inv.uses)).0 = inv.roles;
-                    continue 'new_local; // Break to avoid further borrows of moved variable `inv.code` that
-                           // would happen if we moved the value in `entry()` and then kept on
-                           // looping (since `inv` doesn't change until the outer loop runs again).
+
+    let guild_roles = Backoff::HANDLER.retry(&format!("Getting the roles of guild {}", guild_id), || guild_id.roles(&ctx.http)).await;
+    match (guild_roles, store.list_guild(guild_id.0)) {
+        (Ok(guild_roles), Ok(invites)) => {
+            for inv in invites {
+                for id in resolve_roles(&guild_roles, &inv.roles).1 {
+                    warn!(invite = %inv.code, role = id, "Invite is linked to a role that no longer exists, unlink it with {}invite unlink", prefix);
                 }
             }
-            // Value in local db is not present in Discord anymore
-            println!("Removing invite {} from the local DB as it is no longer present in the guild", inv.code);
-            cached_invite_map.remove(&inv.code);
         }
-        println!("Cached invite map between adding local and http invites: {:?}", cached_invite_map);
+        (Err(why), _) => error!(error = %why, "Could not get the guild's roles"),
+        (_, Err(why)) => error!(error = %why, "Could not read the invite store"),
+    }
 
-        // Add invite codes for things not in local
-        for ac_inv in active_invites {
-            cached_invite_map
-                .entry(ac_inv.code)
-                .or_insert((Vec::<Role>::new(), ac_inv.uses));
+    let drift = drift?;
+    for code in &drift.added {
+        info!(invite = %code, "Tracking invite that was created without us noticing");
+    }
+    for code in &drift.removed {
+        info!(invite = %code, "Removed invite that no longer exists from the store");
+    }
+    for (code, stored, live) in &drift.recounted {
+        info!(invite = %code, stored, live, "Corrected the use count of invite");
+    }
+    if drift.is_empty() {
+        debug!("Stored invites are in sync");
+    }
+    Ok(drift)
+}
+
+// Ignore commands sent in guilds the bot is not configured to serve, and
+// refuse any while shutting down
+#[hook]
+async fn before(ctx: &Context, msg: &Message, command: &str) -> bool {
+    let serves = match msg.guild_id {
+        Some(guild_id) => {
+            let data = ctx.data.read().await;
+            data.get::<BotConfig>().expect("Expected BotConfig in data/typemap").serves(guild_id.0)
+        }
+        None => true,
+    };
+    if serves && health(ctx).await.is_shutting_down() {
+        if let Err(why) = msg.channel_id.say(&ctx.http, "The bot is shutting down, try again in a moment.").await {
+            warn!(error = ?why, "Error sending message");
         }
+        return false;
+    }
+    if serves {
+        // The framework's span is missing the command until now
+        tracing::Span::current().record("command", command);
+        debug!("Running command");
+    }
+    serves
+}
 
-        // Serialise the new vector and write it back to file?
-        let f = fs::File::create(db_path)
-            .expect("Failed to create new file");
-        let mut roles_to_write = Vec::<InviteRoles>::new();
-        for (code, (roles, _uses)) in cached_invite_map.iter() {
-            roles_to_write.push(InviteRoles { code: code.to_string(), roles: roles.to_vec() });
+// Log and count every command that has run
+#[hook]
+async fn after(ctx: &Context, _: &Message, command: &str, result: CommandResult) {
+    if let Err(why) = &result {
+        warn!(error = ?why, "Command failed");
+    }
+    let data = ctx.data.read().await;
+    data.get::<BotMetrics>().expect("Expected BotMetrics in data/typemap").command(command, result.is_ok());
+}
+
+// Tell whoever ran a command why it did not run, instead of ignoring them
+#[hook]
+async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, command: &str) {
+    let prefix = prefix(ctx).await;
+    let reply = match error {
+        DispatchError::CheckFailed(_, Reason::User(reason) | Reason::UserAndLog { user: reason, .. }) => reason,
+        DispatchError::CheckFailed(check, reason) => {
+            warn!(check, ?reason, "Check failed");
+            "You cannot use this command.".to_string()
+        }
+        DispatchError::NotEnoughArguments { min, given } => {
+            format!("This command needs at least {} arguments, but got {}. See {}help {} for its usage.", min, given, prefix, command)
+        }
+        DispatchError::TooManyArguments { max, given } => {
+            format!("This command takes at most {} arguments, but got {}. See {}help {} for its usage.", max, given, prefix, command)
+        }
+        // Only say so once, rather than answer every attempt
+        DispatchError::Ratelimited(info) if info.is_first_try => {
+            format!("This command was used a moment ago, try again in {} seconds.", info.rate_limit.as_secs().max(1))
         }
-        println!("{:?}", roles_to_write);
-        serde_json::to_writer_pretty(f, &roles_to_write)
-            .expect("Failed to write updated JSON");
+        DispatchError::OnlyForGuilds => "This command only works in a server.".to_string(),
+        DispatchError::OnlyForOwners => "Only the bot's owners can use this command.".to_string(),
+        DispatchError::LackingPermissions(permissions) => {
+            format!("You need the {} permission to use this command.", permissions.get_permission_names().join(", "))
+        }
+        DispatchError::LackingRole => "You lack the role needed to use this command.".to_string(),
+        error => {
+            debug!(?error, "Command not run");
+            return;
+        }
+    };
+    if let Err(why) = msg.channel_id.say(&ctx.http, reply).await {
+        warn!(error = ?why, "Error sending message");
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = Options::parse(env::args().skip(1))
+        .unwrap_or_else(|why| fatal(why.exit_code(), why));
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
+    // This will load the environment variables located at `./.env`, relative to
+    // the CWD. See `./.env.example` for an example on how to structure this.
+    // Without one, the variables are expected to be set already.
+    let has_dotenv = dotenv::dotenv().is_ok();
+
+    // Check the whole configuration up front, the token is only needed to
+    // actually connect
+    let config = Config::load(options.config.as_deref(), options.connects())
+        .unwrap_or_else(|why| fatal(EXIT_CONFIG, why));
+    logging::init(&config.logging);
+    if !has_dotenv {
+        debug!("No .env file found, using the environment as is");
+    }
+
+    let db_path = &config.storage.path;
+    let backups = Backups::new(db_path, config.storage.backup_dir.as_deref(), config.storage.backups);
+    if options.restore_backup {
+        match backups.restore() {
+            Ok(Some(backup)) => info!(path = %db_path.display(), backup = %backup.display(), "Restored the invite store"),
+            Ok(None) => fatal(EXIT_NO_STORE, format!("No intact backup of {} found in {}", db_path.display(), backups.dir().display())),
+            Err(why) => fatal(EXIT_IO_ERROR, format!("Could not restore the invite store: {}", why)),
+        }
+        return;
+    }
+
+    // Back the store up before anything (such as a migration) touches it.
+    // A store that fails its check is left alone, so its backups are not
+    // rotated out by broken copies.
+    if config.storage.backups > 0 && db_path.exists() {
+        match store::check(db_path).and_then(|_| backups.take()) {
+            Ok(backup) => debug!(backup = %backup.display(), "Backed up the invite store"),
+            Err(why) => warn!(error = %why, "Could not back up the invite store"),
+        }
+    }
+
+    // Open the invite store before connecting, so a missing or broken store
+    // fails fast. Used to track invites' associated roles and auto-assign
+    // them on join.
+    if !db_path.exists() && !(options.may_create_store() || config.storage.auto_create) {
+        fatal(EXIT_NO_STORE, format!(
+            "The invite store at {} does not exist. Create it with --init or --create-db, or enable storage.auto_create.",
+            db_path.display()
+        ));
+    }
+    let store = store::open(db_path).unwrap_or_else(|why| fatal(EXIT_NO_STORE, format!(
+        "Could not open the invite store at {}: {}. Put its newest intact backup back with --restore-backup.",
+        db_path.display(), why
+    )));
+
+    // Carry over the mappings from the old JSON "database" the first time the
+    // bot runs against a new invite store.
+    if let Some(json_path) = &config.storage.legacy_json {
+        let is_empty = store.list()
+            .unwrap_or_else(|why| fatal(EXIT_NO_STORE, format!("Could not read the invite store: {}", why)))
+            .is_empty();
+        if is_empty {
+            match store::import_legacy_json(store.as_ref(), json_path) {
+                Ok(count) => info!(count, path = %json_path.display(), "Imported invite mappings"),
+                Err(why) => error!(path = %json_path.display(), error = %why, "Could not import invite mappings"),
+            }
+        }
+    }
+
+    if options.init {
+        info!(path = %db_path.display(), "Invite store is ready");
+        return;
+    }
+
+    // Keep backing up while running
+    if let (Some(period), true) = (config.storage.backup_interval, config.storage.backups > 0) {
+        let backups = backups.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick completes right away, and we have just backed up
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let backups = backups.clone();
+                match tokio::task::spawn_blocking(move || backups.take()).await {
+                    Ok(Ok(backup)) => debug!(backup = %backup.display(), "Backed up the invite store"),
+                    Ok(Err(why)) => warn!(error = %why, "Could not back up the invite store"),
+                    Err(why) => error!(error = %why, "Backing up the invite store panicked"),
+                }
+            }
+        });
+    }
+
+    let http = Http::new(&config.token);
+
+    // Get the bot's owners + the bot's id. Discord may not answer right away,
+    // e.g. when the bot is started along with the machine.
+    let info = Backoff::STARTUP.retry("Getting the application info", || http.get_current_application_info()).await
+        .unwrap_or_else(|why| fatal(EXIT_UNAVAILABLE, format!("Could not access the application info: {}", why)));
+    info!(application = %info.name, id = %info.id, "Got the application info");
+    let mut owners = HashSet::new();
+    if let Some(team) = info.team {
+        owners.insert(team.owner_user_id);
     } else {
-        panic!("Error getting active invites from the Discord API");
+        owners.insert(info.owner.id);
+    }
+    let bot_id = Backoff::STARTUP.retry("Getting the bot user", || http.get_current_user()).await
+        .unwrap_or_else(|why| fatal(EXIT_UNAVAILABLE, format!("Could not access the bot ID: {}", why)))
+        .id;
+
+    let framework = StandardFramework::new()
+        .configure(|c| c
+                   .with_whitespace(true)
+                   .on_mention(Some(bot_id))
+                   .prefix(&config.prefix)
+                   .delimiters(config.delimiters.clone())
+                   .owners(owners))
+        .before(before)
+        .after(after)
+        .on_dispatch_error(dispatch_error)
+        // Commands calling Discord for the whole guild, once per guild at a time
+        .bucket("discord", |b| b.delay(5).limit_for(LimitedFor::Guild)).await
+        .help(&HELP)
+        .group(&GENERAL_GROUP)
+        .group(&INVITE_GROUP)
+        .group(&OWNER_GROUP);
+
+    // Bind the monitoring endpoints before connecting, so a bad or taken
+    // address fails fast
+    let metrics = Arc::new(Metrics::default());
+    let health = Arc::new(Health::default());
+    let locks = Arc::new(GuildLocks::default());
+    if let Some(addr) = config.http_listen {
+        let listener = tokio::net::TcpListener::bind(addr).await
+            .unwrap_or_else(|why| fatal(EXIT_UNAVAILABLE, format!("Could not listen on {}: {}", addr, why)));
+        info!(%addr, "Serving monitoring endpoints");
+        let monitor = Arc::new(http::Monitor { metrics: metrics.clone(), health: health.clone(), store: store.clone() });
+        tokio::spawn(http::serve(listener, monitor));
     }
 
+    let mut client = Client::builder(&config.token, config.intents)
+        .event_handler(Handler { reconciling: AtomicBool::new(false) })
+        .framework(TracedFramework(framework))
+        .await
+        .unwrap_or_else(|why| fatal(EXIT_UNAVAILABLE, format!("Error creating client: {:?}", why)));
+    debug!("Built client");
+
     // Explicitly scope this to release the lock after write
     {
         let mut data = client.data.write().await;
@@ -339,12 +920,56 @@ async fn main() {
         // Insert an InviteTracker object into the client data.
         // This is done so that we can access it within events and other
         // methods, as `data` is available through `ctx.data`.
-        data.insert::<InviteTracker>(Arc::new(RwLock::new(cached_invite_map)));
+        data.insert::<InviteTracker>(store);
+        data.insert::<BotConfig>(Arc::new(config));
+        data.insert::<JoinLocks>(locks.clone());
+        data.insert::<BotMetrics>(metrics);
+        data.insert::<BotHealth>(health.clone());
+        data.insert::<ShardManagerContainer>(client.shard_manager.clone());
     }
 
+    // On SIGINT or SIGTERM, refuse new commands and disconnect every shard,
+    // which makes `client.start` return. A second signal exits right away.
+    let shard_manager = client.shard_manager.clone();
+    {
+        let health = health.clone();
+        tokio::spawn(async move {
+            match shutdown::signal().await {
+                Ok(signal) => info!(signal = signal.name(), "Shutting down"),
+                Err(why) => {
+                    error!(error = %why, "Could not listen for signals, shut down by killing the process");
+                    return;
+                }
+            }
+            health.set_shutting_down();
+            shard_manager.lock().await.shutdown_all().await;
+
+            if let Ok(signal) = shutdown::signal().await {
+                fatal(signal.exit_code(), format!("Got {} while shutting down, exiting right away", signal.name()));
+            }
+        });
+    }
 
     if let Err(why) = client.start().await {
-        println!("Error starting client: {:?}", why);
+        fatal(EXIT_UNAVAILABLE, format!("Error starting client: {:?}", why));
+    }
+    if !health.is_shutting_down() {
+        fatal(EXIT_UNAVAILABLE, "The connection to Discord ended unexpectedly");
+    }
+
+    // Let joins that are being attributed finish, so their uses and roles
+    // are stored: a guild's lock is held until its joins are recorded. Every
+    // change is written to the store as it happens, so there is nothing else
+    // to flush. Joins still queued have no record and are caught up on the
+    // next start.
+    match tokio::time::timeout(SHUTDOWN_GRACE, locks.lock_all()).await {
+        Ok(pending) => {
+            let queued = pending.iter().map(|joins| joins.len()).sum::<usize>();
+            if queued > 0 {
+                warn!(queued, "Joins still waiting to be attributed will be caught up on the next start");
+            }
+        }
+        Err(_) => fatal(EXIT_TEMPFAIL, "Gave up waiting for joins to be attributed, they will be caught up on the next start"),
     }
-    println!("Started client");
+    info!("Shut down");
 }
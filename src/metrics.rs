@@ -0,0 +1,134 @@
+/* Counters describing what the bot has been doing, in the Prometheus text
+ * format. They only count up from when the bot started; Prometheus takes care
+ * of restarts. Kept free of serenity so they can be rendered without a
+ * connection. */
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A counter that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Joins attributed to a single invite.
+    pub joins_attributed: Counter,
+    /// Joins that could have come through more than one invite.
+    pub joins_ambiguous: Counter,
+    /// Joins through an invite that could not be found.
+    pub joins_unknown: Counter,
+    /// Joins that could not be attributed as the store could not be read.
+    pub joins_failed: Counter,
+    /// Roles given to members that joined.
+    pub roles_assigned: Counter,
+    /// Roles that could not be given to members that joined.
+    pub roles_failed: Counter,
+    pub gateway_resumes: Counter,
+    /// Commands run, by name and outcome.
+    commands: Mutex<BTreeMap<(String, &'static str), u64>>,
+}
+
+impl Metrics {
+    /// Count a command that has run, successfully or not.
+    pub fn command(&self, name: &str, ok: bool) {
+        let outcome = if ok { "ok" } else { "error" };
+        let mut commands = self.commands.lock().expect("Metrics lock poisoned");
+        *commands.entry((name.to_string(), outcome)).or_default() += 1;
+    }
+
+    /// The metrics in the Prometheus text format. `invites` is the number of
+    /// invites in the store, if it could be read.
+    pub fn render(&self, invites: Option<usize>) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "tcysm_joins_total", "counter", "Members that joined, by how the invite they used was attributed.");
+        for (outcome, counter) in [
+            ("attributed", &self.joins_attributed),
+            ("ambiguous", &self.joins_ambiguous),
+            ("unknown", &self.joins_unknown),
+            ("error", &self.joins_failed),
+        ] {
+            let _ = writeln!(out, "tcysm_joins_total{{outcome=\"{}\"}} {}", outcome, counter.get());
+        }
+
+        header(&mut out, "tcysm_roles_assigned_total", "counter", "Roles given to members that joined.");
+        let _ = writeln!(out, "tcysm_roles_assigned_total {}", self.roles_assigned.get());
+        header(&mut out, "tcysm_role_assignment_failures_total", "counter", "Roles that could not be given to members that joined.");
+        let _ = writeln!(out, "tcysm_role_assignment_failures_total {}", self.roles_failed.get());
+
+        header(&mut out, "tcysm_commands_total", "counter", "Commands run, by command and outcome.");
+        for ((name, outcome), count) in self.commands.lock().expect("Metrics lock poisoned").iter() {
+            let _ = writeln!(out, "tcysm_commands_total{{command=\"{}\",outcome=\"{}\"}} {}", escape(name), outcome, count);
+        }
+
+        header(&mut out, "tcysm_gateway_resumes_total", "counter", "Gateway sessions resumed after a disconnect.");
+        let _ = writeln!(out, "tcysm_gateway_resumes_total {}", self.gateway_resumes.get());
+
+        if let Some(invites) = invites {
+            header(&mut out, "tcysm_invites_tracked", "gauge", "Invites in the invite store.");
+            let _ = writeln!(out, "tcysm_invites_tracked {}", invites);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_metric_has_help_and_type() {
+        let out = Metrics::default().render(Some(3));
+        let lines = out.lines().collect::<Vec<_>>();
+        for sample in lines.iter().filter(|line| !line.starts_with('#')) {
+            let name = sample.split(['{', ' ']).next().unwrap();
+            let help = lines.iter().position(|line| line.starts_with(&format!("# HELP {} ", name))).unwrap();
+            assert_eq!(lines[help + 1], format!("# TYPE {} {}", name, if name == "tcysm_invites_tracked" { "gauge" } else { "counter" }));
+        }
+        assert!(lines.contains(&"tcysm_joins_total{outcome=\"attributed\"} 0"));
+        assert!(lines.contains(&"tcysm_invites_tracked 3"));
+    }
+
+    #[test]
+    fn the_invite_gauge_is_left_out_when_unknown() {
+        assert!(!Metrics::default().render(None).contains("tcysm_invites_tracked"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::default();
+        metrics.command("say \"hi\"\\\nbye", false);
+        metrics.command("say \"hi\"\\\nbye", false);
+        metrics.command("link", true);
+
+        let out = metrics.render(None);
+        assert!(out.contains("tcysm_commands_total{command=\"link\",outcome=\"ok\"} 1\n"));
+        assert!(out.contains("tcysm_commands_total{command=\"say \\\"hi\\\"\\\\\\nbye\",outcome=\"error\"} 2\n"));
+    }
+}
@@ -0,0 +1,341 @@
+/* Resolving the roles given to commands. A role can be given as a mention,
+ * an ID or its name, in any case. Names that match nothing get the closest
+ * names suggested, and names matching several roles are refused rather than
+ * guessed at.
+ *
+ * Also checks that a role can and may be given to members before it is
+ * linked, rather than finding out when a member joins. */
+use std::fmt;
+
+use serenity::model::permissions::Permissions;
+
+// How many roles to suggest, or to list when a name is ambiguous
+const MAX_CANDIDATES: usize = 5;
+
+/// Why an argument is not a role.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoleError {
+    /// A mention or ID of a role that does not exist, e.g. as it was deleted.
+    UnknownId(u64),
+    /// No role has that name. Holds the closest names, if any are close.
+    NotFound { arg: String, suggestions: Vec<String> },
+    /// Several roles have that name, listed with their IDs.
+    Ambiguous { arg: String, candidates: Vec<(u64, String)> },
+}
+
+impl fmt::Display for RoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoleError::UnknownId(id) => write!(f, "No role with ID {} found.", id),
+            RoleError::NotFound { arg, suggestions } if suggestions.is_empty() => write!(f, "No role {} found.", arg),
+            RoleError::NotFound { arg, suggestions } => write!(f, "No role {} found, did you mean {}?", arg, suggestions.join(" or ")),
+            RoleError::Ambiguous { arg, candidates } => {
+                let candidates = candidates.iter().map(|(id, name)| format!("{} ({})", name, id)).collect::<Vec<String>>();
+                write!(f, "{} matches several roles: {}. Give the ID of the one you mean.", arg, candidates.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for RoleError {}
+
+/// Find the role `arg` refers to among `roles`, given as ID and name pairs.
+/// In order, `arg` may be a role mention (`<@&id>`), an ID, an exact name,
+/// a name in a different case, or a name with `@` in front.
+pub fn resolve(roles: &[(u64, &str)], arg: &str) -> Result<u64, RoleError> {
+    let arg = arg.trim();
+    let id = arg.strip_prefix("<@&").and_then(|id| id.strip_suffix('>')).unwrap_or(arg);
+    if let Ok(id) = id.parse::<u64>() {
+        // Names can be numbers too
+        if !roles.iter().any(|(_, name)| *name == arg) {
+            return if roles.iter().any(|(role, _)| *role == id) { Ok(id) } else { Err(RoleError::UnknownId(id)) };
+        }
+    }
+
+    let by_name = |matches: &dyn Fn(&str) -> bool| roles.iter().filter(|(_, name)| matches(name)).collect::<Vec<_>>();
+    let lower = arg.to_lowercase();
+    let mut found = by_name(&|name| name == arg);
+    if found.is_empty() {
+        found = by_name(&|name| name.to_lowercase() == lower);
+    }
+    // Role names that are not mentionable are often typed as `@Name`
+    if let (true, Some(rest)) = (found.is_empty(), arg.strip_prefix('@')) {
+        let rest = rest.to_lowercase();
+        found = by_name(&|name| name.to_lowercase() == rest);
+    }
+
+    match found.as_slice() {
+        [(id, _)] => Ok(*id),
+        [] => Err(RoleError::NotFound { arg: arg.to_string(), suggestions: suggest(roles, &lower) }),
+        _ => Err(RoleError::Ambiguous {
+            arg: arg.to_string(),
+            candidates: found.iter().take(MAX_CANDIDATES).map(|(id, name)| (*id, name.to_string())).collect(),
+        }),
+    }
+}
+
+/// The names closest to `arg`, closest first: those containing it, or within
+/// a few typos of it.
+fn suggest(roles: &[(u64, &str)], arg: &str) -> Vec<String> {
+    let max_distance = (arg.chars().count() / 3).max(1);
+    let mut close = roles.iter()
+        .filter_map(|(_, name)| {
+            let lower = name.to_lowercase();
+            let distance = if lower.contains(arg) { 0 } else { distance(&lower, arg) };
+            (distance <= max_distance).then_some((distance, name.to_string()))
+        })
+        .collect::<Vec<(usize, String)>>();
+    close.sort();
+    close.dedup_by(|a, b| a.1 == b.1);
+    close.into_iter().take(MAX_CANDIDATES).map(|(_, name)| name).collect()
+}
+
+/// The Levenshtein distance between `a` and `b`: how many characters must
+/// be inserted, deleted or replaced to turn one into the other.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(ca != *cb);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The permissions that can be named in `deny_permissions`: those that let a
+/// member moderate or take over a guild.
+pub const DANGEROUS_PERMISSIONS: &[(&str, Permissions)] = &[
+    ("administrator", Permissions::ADMINISTRATOR),
+    ("manage_guild", Permissions::MANAGE_GUILD),
+    ("manage_roles", Permissions::MANAGE_ROLES),
+    ("manage_channels", Permissions::MANAGE_CHANNELS),
+    ("manage_webhooks", Permissions::MANAGE_WEBHOOKS),
+    ("manage_messages", Permissions::MANAGE_MESSAGES),
+    ("manage_nicknames", Permissions::MANAGE_NICKNAMES),
+    ("manage_emojis_and_stickers", Permissions::MANAGE_EMOJIS_AND_STICKERS),
+    ("manage_events", Permissions::MANAGE_EVENTS),
+    ("manage_threads", Permissions::MANAGE_THREADS),
+    ("kick_members", Permissions::KICK_MEMBERS),
+    ("ban_members", Permissions::BAN_MEMBERS),
+    ("moderate_members", Permissions::MODERATE_MEMBERS),
+    ("mention_everyone", Permissions::MENTION_EVERYONE),
+    ("view_audit_log", Permissions::VIEW_AUDIT_LOG),
+];
+
+/// The roles the bot refuses to give, whoever links them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenyList {
+    /// Names or IDs of roles.
+    pub roles: Vec<String>,
+    /// Roles granting any of these are refused.
+    pub permissions: Permissions,
+}
+
+/// A role, as far as giving it to members is concerned.
+#[derive(Debug, Clone, Copy)]
+pub struct RoleInfo<'a> {
+    pub id: u64,
+    pub name: &'a str,
+    pub position: i64,
+    /// Whether the role belongs to an integration or bot, and can only be
+    /// given by Discord.
+    pub managed: bool,
+    pub permissions: Permissions,
+}
+
+/// What the bot may do with roles in a guild.
+#[derive(Debug, Clone, Copy)]
+pub struct BotRank {
+    /// The position of the bot's highest role. It can only give roles below.
+    pub top_position: i64,
+    pub permissions: Permissions,
+}
+
+/// Why a role cannot be linked to an invite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ungrantable {
+    Everyone,
+    Managed,
+    /// The bot lacks the Manage Roles permission.
+    NoManageRoles,
+    /// The role is not below the bot's highest role.
+    AboveBot,
+    /// The role is on the deny-list.
+    Denied,
+    /// The role grants permissions on the deny-list, named here.
+    Dangerous(Vec<&'static str>),
+}
+
+impl fmt::Display for Ungrantable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ungrantable::Everyone => write!(f, "every member has it already"),
+            Ungrantable::Managed => write!(f, "it is managed by an integration"),
+            Ungrantable::NoManageRoles => write!(f, "the bot lacks the Manage Roles permission"),
+            Ungrantable::AboveBot => write!(f, "it is not below the bot's highest role"),
+            Ungrantable::Denied => write!(f, "it is on the bot's deny-list"),
+            Ungrantable::Dangerous(permissions) => write!(f, "it grants {}", permissions.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for Ungrantable {}
+
+/// Check that the bot can give `role` to members of the guild `guild_id`, and
+/// that `deny` does not forbid it.
+pub fn check_grantable(role: &RoleInfo, guild_id: u64, bot: &BotRank, deny: &DenyList) -> Result<(), Ungrantable> {
+    // The @everyone role has the guild's ID
+    if role.id == guild_id {
+        return Err(Ungrantable::Everyone);
+    }
+    if role.managed {
+        return Err(Ungrantable::Managed);
+    }
+    if deny.roles.iter().any(|denied| *denied == role.id.to_string() || denied.eq_ignore_ascii_case(role.name)) {
+        return Err(Ungrantable::Denied);
+    }
+    let dangerous = DANGEROUS_PERMISSIONS.iter()
+        .filter(|(_, permission)| deny.permissions.contains(*permission) && role.permissions.contains(*permission))
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>();
+    if !dangerous.is_empty() {
+        return Err(Ungrantable::Dangerous(dangerous));
+    }
+    if !bot.permissions.intersects(Permissions::MANAGE_ROLES | Permissions::ADMINISTRATOR) {
+        return Err(Ungrantable::NoManageRoles);
+    }
+    if role.position >= bot.top_position {
+        return Err(Ungrantable::AboveBot);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLES: &[(u64, &str)] = &[(1, "@everyone"), (20, "Alumni"), (30, "Event Staff"), (40, "2024"), (50, "Mod"), (51, "mod")];
+
+    #[test]
+    fn mentions_and_ids() {
+        assert_eq!(resolve(ROLES, "<@&20>"), Ok(20));
+        assert_eq!(resolve(ROLES, "30"), Ok(30));
+        assert_eq!(resolve(ROLES, " 20 "), Ok(20));
+        assert_eq!(resolve(ROLES, "<@&99>"), Err(RoleError::UnknownId(99)));
+        assert_eq!(resolve(ROLES, "99"), Err(RoleError::UnknownId(99)));
+        // A name made of digits is taken as the name
+        assert_eq!(resolve(ROLES, "2024"), Ok(40));
+    }
+
+    #[test]
+    fn names_in_any_case() {
+        assert_eq!(resolve(ROLES, "Event Staff"), Ok(30));
+        assert_eq!(resolve(ROLES, "event staff"), Ok(30));
+        assert_eq!(resolve(ROLES, "@alumni"), Ok(20));
+        assert_eq!(resolve(ROLES, "@everyone"), Ok(1));
+        // An exact match wins over names differing in case
+        assert_eq!(resolve(ROLES, "mod"), Ok(51));
+    }
+
+    #[test]
+    fn names_matching_several_roles_are_ambiguous() {
+        let roles = [(20, "Alumni"), (21, "Alumni")];
+        assert_eq!(resolve(&roles, "Alumni"), Err(RoleError::Ambiguous {
+            arg: "Alumni".to_string(),
+            candidates: vec![(20, "Alumni".to_string()), (21, "Alumni".to_string())],
+        }));
+        assert!(matches!(resolve(ROLES, "MOD"), Err(RoleError::Ambiguous { candidates, .. }) if candidates.len() == 2));
+    }
+
+    #[test]
+    fn close_names_are_suggested() {
+        let not_found = |arg: &str, suggestions: &[&str]| Err(RoleError::NotFound {
+            arg: arg.to_string(),
+            suggestions: suggestions.iter().map(|name| name.to_string()).collect(),
+        });
+        assert_eq!(resolve(ROLES, "Alumi"), not_found("Alumi", &["Alumni"]));
+        assert_eq!(resolve(ROLES, "staff"), not_found("staff", &["Event Staff"]));
+        assert_eq!(resolve(ROLES, "Teachers"), not_found("Teachers", &[]));
+        // Short names allow a single typo only
+        assert_eq!(resolve(ROLES, "Mud"), not_found("Mud", &["Mod", "mod"]));
+        assert_eq!(resolve(ROLES, "Max"), not_found("Max", &[]));
+    }
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("alumni", "alumni"), 0);
+    }
+
+    const GUILD: u64 = 1;
+
+    fn role(id: u64, position: i64) -> RoleInfo<'static> {
+        RoleInfo { id, name: "Alumni", position, managed: false, permissions: Permissions::empty() }
+    }
+
+    fn bot() -> BotRank {
+        BotRank { top_position: 5, permissions: Permissions::MANAGE_ROLES }
+    }
+
+    fn deny() -> DenyList {
+        DenyList { roles: vec!["Staff".to_string(), "30".to_string()], permissions: Permissions::ADMINISTRATOR | Permissions::MANAGE_GUILD }
+    }
+
+    #[test]
+    fn roles_below_the_bot_can_be_given() {
+        assert_eq!(check_grantable(&role(20, 4), GUILD, &bot(), &deny()), Ok(()));
+        let admin = BotRank { permissions: Permissions::ADMINISTRATOR, ..bot() };
+        assert_eq!(check_grantable(&role(20, 4), GUILD, &admin, &deny()), Ok(()));
+    }
+
+    #[test]
+    fn everyone_cannot_be_given() {
+        assert_eq!(check_grantable(&role(GUILD, 0), GUILD, &bot(), &deny()), Err(Ungrantable::Everyone));
+    }
+
+    #[test]
+    fn managed_roles_cannot_be_given() {
+        let managed = RoleInfo { managed: true, ..role(20, 1) };
+        assert_eq!(check_grantable(&managed, GUILD, &bot(), &deny()), Err(Ungrantable::Managed));
+    }
+
+    #[test]
+    fn denied_roles_cannot_be_given() {
+        let staff = RoleInfo { name: "staff", ..role(20, 1) };
+        assert_eq!(check_grantable(&staff, GUILD, &bot(), &deny()), Err(Ungrantable::Denied));
+        assert_eq!(check_grantable(&role(30, 1), GUILD, &bot(), &deny()), Err(Ungrantable::Denied));
+    }
+
+    #[test]
+    fn roles_with_denied_permissions_cannot_be_given() {
+        let dangerous = RoleInfo { permissions: Permissions::MANAGE_GUILD | Permissions::ADMINISTRATOR | Permissions::KICK_MEMBERS, ..role(20, 1) };
+        assert_eq!(check_grantable(&dangerous, GUILD, &bot(), &deny()), Err(Ungrantable::Dangerous(vec!["administrator", "manage_guild"])));
+        // Permissions left off the deny-list are fine
+        let kick = RoleInfo { permissions: Permissions::KICK_MEMBERS, ..role(20, 1) };
+        assert_eq!(check_grantable(&kick, GUILD, &bot(), &deny()), Ok(()));
+    }
+
+    #[test]
+    fn bot_needs_manage_roles() {
+        let bot = BotRank { permissions: Permissions::KICK_MEMBERS, ..bot() };
+        assert_eq!(check_grantable(&role(20, 1), GUILD, &bot, &deny()), Err(Ungrantable::NoManageRoles));
+    }
+
+    #[test]
+    fn roles_at_or_above_the_bot_cannot_be_given() {
+        assert_eq!(check_grantable(&role(20, 5), GUILD, &bot(), &deny()), Err(Ungrantable::AboveBot));
+        assert_eq!(check_grantable(&role(20, 6), GUILD, &bot(), &deny()), Err(Ungrantable::AboveBot));
+    }
+
+    #[test]
+    fn errors_read_as_sentences() {
+        let why = resolve(ROLES, "Alumi").unwrap_err();
+        assert_eq!(why.to_string(), "No role Alumi found, did you mean Alumni?");
+        assert_eq!(RoleError::UnknownId(99).to_string(), "No role with ID 99 found.");
+    }
+}
@@ -0,0 +1,47 @@
+/* Stopping on SIGINT or SIGTERM, as sent by Ctrl+C, systemd or a container
+ * runtime. The first signal shuts the bot down cleanly; a second one means
+ * whoever sent it is done waiting. */
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+impl Signal {
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Interrupt => "SIGINT",
+            Signal::Terminate => "SIGTERM",
+        }
+    }
+
+    /// The exit code of a process killed by the signal, as shells report it.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Signal::Interrupt => 128 + 2,
+            Signal::Terminate => 128 + 15,
+        }
+    }
+}
+
+/// Wait for the next SIGINT or SIGTERM.
+#[cfg(unix)]
+pub async fn signal() -> io::Result<Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => Ok(Signal::Interrupt),
+        _ = terminate.recv() => Ok(Signal::Terminate),
+    }
+}
+
+/// Wait for the next Ctrl+C, the only signal there is elsewhere.
+#[cfg(not(unix))]
+pub async fn signal() -> io::Result<Signal> {
+    tokio::signal::ctrl_c().await?;
+    Ok(Signal::Interrupt)
+}
@@ -0,0 +1,153 @@
+/* Statistics over the join log: how many members each invite brought in, how
+ * many of them stayed, and when they joined. Everything is computed from
+ * `JoinRecord`s, so it works the same for every store backend. */
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::store::JoinRecord;
+
+const WEEK: i64 = 7 * 24 * 60 * 60;
+
+/// Join counts for a set of joins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Every recorded join.
+    pub joins: usize,
+    /// Joins we could attribute to a single invite.
+    pub attributed: usize,
+    /// Attributed joins whose member has not left the guild since.
+    pub retained: usize,
+}
+
+impl Summary {
+    pub fn of<'a>(joins: impl IntoIterator<Item = &'a JoinRecord>) -> Self {
+        let mut summary = Summary::default();
+        for join in joins {
+            summary.add(join);
+        }
+        summary
+    }
+
+    fn add(&mut self, join: &JoinRecord) {
+        self.joins += 1;
+        if join.invite.is_some() {
+            self.attributed += 1;
+            if join.left_at.is_none() {
+                self.retained += 1;
+            }
+        }
+    }
+
+    /// Share of attributed members that are still in the guild, in percent.
+    pub fn retention(&self) -> Option<u32> {
+        (self.attributed > 0).then(|| (self.retained * 100 / self.attributed) as u32)
+    }
+}
+
+/// The number of joins in each of the last `weeks` weeks before `now`,
+/// oldest week first.
+pub fn joins_per_week(joins: &[JoinRecord], now: i64, weeks: usize) -> Vec<usize> {
+    let mut counts = vec![0; weeks];
+    for join in joins {
+        let ago = ((now - join.joined_at).max(0) / WEEK) as usize;
+        if ago < weeks {
+            counts[weeks - 1 - ago] += 1;
+        }
+    }
+    counts
+}
+
+/// Group the joins by `key`, skipping joins without one, and rank the groups
+/// by how many joins they have. Ties are ranked by how many members stayed.
+pub fn leaderboard<K: Eq + Hash + Ord>(joins: &[JoinRecord], key: impl Fn(&JoinRecord) -> Option<K>) -> Vec<(K, Summary)> {
+    let mut groups = HashMap::<K, Summary>::new();
+    for join in joins {
+        if let Some(k) = key(join) {
+            groups.entry(k).or_default().add(join);
+        }
+    }
+
+    let mut ranked = groups.into_iter().collect::<Vec<_>>();
+    ranked.sort_by(|(ka, a), (kb, b)| b.joins.cmp(&a.joins).then(b.retained.cmp(&a.retained)).then(ka.cmp(kb)));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Confidence;
+
+    const NOW: i64 = 100 * WEEK;
+
+    fn join(invite: Option<&str>, joined_at: i64, left: bool) -> JoinRecord {
+        JoinRecord {
+            guild_id: 1,
+            user_id: 2,
+            joined_at,
+            invite: invite.map(String::from),
+            inviter: None,
+            roles: Vec::new(),
+            confidence: if invite.is_some() { Confidence::Certain } else { Confidence::Unknown },
+            left_at: left.then_some(joined_at + 60),
+        }
+    }
+
+    #[test]
+    fn summaries_count_attributed_and_retained_joins() {
+        let joins = [join(Some("a"), NOW, false), join(Some("a"), NOW, true), join(Some("b"), NOW, false), join(None, NOW, false)];
+        let summary = Summary::of(&joins);
+        assert_eq!(summary, Summary { joins: 4, attributed: 3, retained: 2 });
+        assert_eq!(summary.retention(), Some(66));
+    }
+
+    #[test]
+    fn weeks_end_at_now() {
+        let joins = [
+            join(None, NOW, false),
+            join(None, NOW - WEEK + 1, false),
+            // Exactly a week ago is the week before
+            join(None, NOW - WEEK, false),
+            join(None, NOW - 3 * WEEK + 1, false),
+            // Too old to show up
+            join(None, NOW - 3 * WEEK, false),
+            // Clocks disagree: counted as this week
+            join(None, NOW + 5, false),
+        ];
+        assert_eq!(joins_per_week(&joins, NOW, 3), vec![1, 1, 3]);
+    }
+
+    #[test]
+    fn an_empty_guild_has_no_stats() {
+        assert_eq!(Summary::of(&[]), Summary::default());
+        assert_eq!(Summary::default().retention(), None);
+        assert_eq!(joins_per_week(&[], NOW, 4), vec![0; 4]);
+        assert!(leaderboard(&[], |join| join.invite.clone()).is_empty());
+    }
+
+    #[test]
+    fn leaderboard_ranks_by_joins_then_retention_then_key() {
+        let joins = [
+            join(Some("few"), NOW, false),
+            join(Some("left"), NOW, true),
+            join(Some("left"), NOW, true),
+            join(Some("stayed"), NOW, false),
+            join(Some("stayed"), NOW, true),
+            join(Some("most"), NOW, true),
+            join(Some("most"), NOW, true),
+            join(Some("most"), NOW, true),
+            join(Some("also few"), NOW, false),
+            join(None, NOW, false),
+        ];
+        let ranked = leaderboard(&joins, |join| join.invite.clone())
+            .into_iter()
+            .map(|(invite, summary)| (invite, summary.joins, summary.retained))
+            .collect::<Vec<_>>();
+        assert_eq!(ranked, vec![
+            ("most".to_string(), 3, 0),
+            ("stayed".to_string(), 2, 1),
+            ("left".to_string(), 2, 0),
+            ("also few".to_string(), 1, 1),
+            ("few".to_string(), 1, 1),
+        ]);
+    }
+}
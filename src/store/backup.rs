@@ -0,0 +1,222 @@
+/* Rotating backups of the invite store, taken whenever the bot starts and
+ * every `storage.backup_hours` after. Each one is a consistent snapshot named
+ * after when it was taken, e.g. `invites.db.2026-10-18T093000Z.bak`, so they
+ * sort oldest first. `--restore-backup` puts the newest intact one back. */
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::warn;
+
+use super::{write_atomically, JsonStore, SqliteStore, StoreResult};
+
+#[derive(Debug, Clone)]
+pub struct Backups {
+    store: PathBuf,
+    dir: PathBuf,
+    keep: usize,
+}
+
+impl Backups {
+    /// Backups of the store at `store`, kept in `dir`, or next to the store if
+    /// not given. Only the newest `keep` are kept.
+    pub fn new(store: &Path, dir: Option<&Path>, keep: usize) -> Self {
+        let dir = dir.or_else(|| store.parent()).filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        Backups { store: store.to_path_buf(), dir: dir.to_path_buf(), keep }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Snapshot the store, then delete all but the newest backups. Returns
+    /// the new backup.
+    pub fn take(&self) -> StoreResult<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        let backup = self.dir.join(format!("{}.{}.bak", self.name(), timestamp(now())));
+        if self.is_json() {
+            // The JSON store replaces its file in one go, so any copy is whole
+            write_atomically(&backup, &fs::read(&self.store)?)?;
+        } else {
+            SqliteStore::snapshot(&self.store, &backup)?;
+        }
+
+        for old in self.list()?.into_iter().skip(self.keep) {
+            fs::remove_file(old)?;
+        }
+        Ok(backup)
+    }
+
+    /// Every backup, newest first.
+    pub fn list(&self) -> io::Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(why) if why.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(why) => return Err(why),
+        };
+
+        let prefix = format!("{}.", self.name());
+        let mut backups = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(".bak") {
+                backups.push(self.dir.join(&*name));
+            }
+        }
+        backups.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(backups)
+    }
+
+    /// Put the newest intact backup in place of the store. The store, and any
+    /// SQLite journal next to it, is moved aside rather than deleted. Returns
+    /// the backup that was restored, or `None` if none of them is intact.
+    pub fn restore(&self) -> StoreResult<Option<PathBuf>> {
+        let mut intact = None;
+        for backup in self.list()? {
+            let checked = if self.is_json() { JsonStore::check(&backup) } else { SqliteStore::check(&backup) };
+            match checked {
+                Ok(()) => {
+                    intact = Some(backup);
+                    break;
+                }
+                Err(why) => warn!(backup = %backup.display(), error = %why, "Skipping backup that is not intact"),
+            }
+        }
+        let backup = match intact {
+            Some(backup) => backup,
+            None => return Ok(None),
+        };
+
+        // A journal left next to the store would be applied to the restored
+        // database the next time it is opened
+        let aside = format!(".{}.broken", timestamp(now()));
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let path = with_suffix(&self.store, suffix);
+            match fs::rename(&path, with_suffix(&path, &aside)) {
+                Err(why) if why.kind() != ErrorKind::NotFound => return Err(why.into()),
+                _ => {}
+            }
+        }
+        write_atomically(&self.store, &fs::read(&backup)?)?;
+        Ok(Some(backup))
+    }
+
+    fn name(&self) -> String {
+        self.store.file_name().map_or_else(|| "invites".to_string(), |name| name.to_string_lossy().into_owned())
+    }
+
+    fn is_json(&self) -> bool {
+        self.store.extension().is_some_and(|ext| ext == "json")
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// `secs` since the Unix epoch as a UTC date and time that sorts as text and
+/// can be used in file names, e.g. `2026-10-18T093000Z`.
+fn timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}{:02}{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// The year, month and day `days` after 1970-01-01, using Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::scratch_dir;
+
+    const STORE: &str = r#"{"invites": []}"#;
+
+    #[test]
+    fn days_become_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 2024-02-29, a leap day, and the days either side of it
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19781), (2024, 2, 28));
+        assert_eq!(civil_from_days(19783), (2024, 3, 1));
+        // The turn of a year
+        assert_eq!(civil_from_days(20453), (2025, 12, 31));
+        assert_eq!(civil_from_days(20454), (2026, 1, 1));
+    }
+
+    #[test]
+    fn timestamps_sort_as_text() {
+        assert_eq!(timestamp(0), "1970-01-01T000000Z");
+        assert_eq!(timestamp(1_792_315_800), "2026-10-18T093000Z");
+        assert!(timestamp(1_767_225_599) < timestamp(1_767_225_600));
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest() {
+        let dir = scratch_dir("backup-rotation");
+        let store = dir.join("invites.json");
+        fs::write(&store, STORE).unwrap();
+        for old in ["2020-01-01T000000Z", "2021-01-01T000000Z", "2022-01-01T000000Z"] {
+            fs::write(dir.join(format!("invites.json.{}.bak", old)), STORE).unwrap();
+        }
+        // Not a backup of this store
+        fs::write(dir.join("other.json.2019-01-01T000000Z.bak"), STORE).unwrap();
+
+        let backups = Backups::new(&store, None, 2);
+        let taken = backups.take().unwrap();
+        assert_eq!(backups.list().unwrap(), vec![taken, dir.join("invites.json.2022-01-01T000000Z.bak")]);
+        assert!(dir.join("other.json.2019-01-01T000000Z.bak").exists());
+    }
+
+    #[test]
+    fn restore_skips_broken_backups_and_keeps_the_store() {
+        let dir = scratch_dir("backup-restore");
+        let store = dir.join("invites.json");
+        fs::write(&store, "damaged").unwrap();
+        let intact = dir.join("invites.json.2021-01-01T000000Z.bak");
+        fs::write(&intact, STORE).unwrap();
+        fs::write(dir.join("invites.json.2022-01-01T000000Z.bak"), "{ not json").unwrap();
+
+        let backups = Backups::new(&store, Some(&dir), 7);
+        assert_eq!(backups.restore().unwrap(), Some(intact));
+        assert_eq!(fs::read_to_string(&store).unwrap(), STORE);
+
+        let aside = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().ends_with(".broken"))
+            .collect::<Vec<_>>();
+        assert_eq!(aside.len(), 1);
+        assert_eq!(fs::read_to_string(&aside[0]).unwrap(), "damaged");
+    }
+
+    #[test]
+    fn restore_without_intact_backups_changes_nothing() {
+        let dir = scratch_dir("backup-none");
+        let store = dir.join("invites.json");
+        fs::write(&store, "damaged").unwrap();
+        fs::write(dir.join("invites.json.2022-01-01T000000Z.bak"), "{ not json").unwrap();
+
+        assert_eq!(Backups::new(&store, None, 7).restore().unwrap(), None);
+        assert_eq!(fs::read_to_string(&store).unwrap(), "damaged");
+    }
+}
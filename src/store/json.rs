@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+use super::memory::Invites;
+use super::{write_atomically, GuildSettings, JoinFilter, JoinRecord, LiveInvite, MappingStore, StoreResult, StoredInvite};
+
+/// What the JSON file looks like on disk.
+#[derive(Serialize, Deserialize, Default)]
+struct JsonFile {
+    invites: Vec<StoredInvite>,
+    #[serde(default)]
+    guilds: BTreeMap<u64, GuildSettings>,
+    #[serde(default)]
+    counted_at: BTreeMap<u64, i64>,
+    #[serde(default)]
+    joins: Vec<JoinRecord>,
+}
+
+
+/// Keeps every mapping in memory and rewrites the whole JSON file after each
+/// change, atomically so a crash cannot leave a half-written file behind.
+/// Fine for the handful of invites a single server has, but prefer
+/// `SqliteStore` for anything bigger.
+pub struct JsonStore {
+    path: PathBuf,
+    invites: Mutex<Invites>,
+}
+
+impl JsonStore {
+    /// Load the mappings at `path`, creating an empty file if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = match fs::read_to_string(&path) {
+            Ok(contents) => parse(&contents)?,
+            Err(why) if why.kind() == ErrorKind::NotFound => {
+                let file = JsonFile::default();
+                write_atomically(&path, &serde_json::to_vec_pretty(&file)?)?;
+                file
+            }
+            Err(why) => return Err(why.into()),
+        };
+
+        Ok(JsonStore { path, invites: Mutex::new(Invites::new(file.invites, file.guilds, file.counted_at, file.joins)) })
+    }
+
+    /// Check that the file at `path` can be read, without changing it.
+    pub fn check<P: AsRef<Path>>(path: P) -> StoreResult<()> {
+        parse(&fs::read_to_string(path)?)?;
+        Ok(())
+    }
+
+    fn invites(&self) -> MutexGuard<'_, Invites> {
+        self.invites.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    /// Apply `change` to a copy of the mappings and write that copy to disk.
+    /// The in-memory mappings are only replaced once the write succeeded.
+    fn modify<T>(&self, change: impl FnOnce(&mut Invites) -> T) -> StoreResult<T> {
+        let mut invites = self.invites();
+        let mut updated = invites.clone();
+        let result = change(&mut updated);
+
+        let file = JsonFile {
+            invites: updated.list(),
+            guilds: updated.guilds().clone(),
+            counted_at: updated.all_counted_at().clone(),
+            joins: updated.all_joins().to_vec(),
+        };
+        write_atomically(&self.path, &serde_json::to_vec_pretty(&file)?)?;
+        *invites = updated;
+
+        Ok(result)
+    }
+}
+
+fn parse(contents: &str) -> StoreResult<JsonFile> {
+    // Files written before guild settings existed are a bare list of invites
+    match serde_json::from_str::<serde_json::Value>(contents)? {
+        invites @ serde_json::Value::Array(_) => Ok(JsonFile { invites: serde_json::from_value(invites)?, ..Default::default() }),
+        file => Ok(serde_json::from_value(file)?),
+    }
+}
+
+impl MappingStore for JsonStore {
+    fn get(&self, guild_id: u64, code: &str) -> StoreResult<Option<StoredInvite>> {
+        Ok(self.invites().get(guild_id, code))
+    }
+
+    fn list(&self) -> StoreResult<Vec<StoredInvite>> {
+        Ok(self.invites().list())
+    }
+
+    fn upsert_invite(&self, guild_id: u64, invite: &LiveInvite) -> StoreResult<()> {
+        self.modify(|invites| invites.upsert_invite(guild_id, invite))
+    }
+
+    fn save_invite(&self, invite: &StoredInvite) -> StoreResult<()> {
+        self.modify(|invites| invites.save_invite(invite))
+    }
+
+    fn link(&self, guild_id: u64, code: &str, roles: &[u64]) -> StoreResult<Vec<u64>> {
+        self.modify(|invites| invites.link(guild_id, code, roles))
+    }
+
+    fn unlink(&self, code: &str, roles: Option<&[u64]>) -> StoreResult<Vec<u64>> {
+        self.modify(|invites| invites.unlink(code, roles))
+    }
+
+    fn set_label(&self, code: &str, label: Option<&str>) -> StoreResult<()> {
+        self.modify(|invites| invites.set_label(code, label))
+    }
+
+    fn set_note(&self, code: &str, note: Option<&str>) -> StoreResult<()> {
+        self.modify(|invites| invites.set_note(code, note))
+    }
+
+    fn record_uses(&self, code: &str, uses: u64) -> StoreResult<()> {
+        self.modify(|invites| invites.record_uses(code, uses))
+    }
+
+    fn remove_invite(&self, code: &str) -> StoreResult<()> {
+        self.modify(|invites| invites.remove_invite(code))
+    }
+
+    fn guild_settings(&self, guild_id: u64) -> StoreResult<GuildSettings> {
+        Ok(self.invites().guild_settings(guild_id))
+    }
+
+    fn set_guild_settings(&self, guild_id: u64, settings: &GuildSettings) -> StoreResult<()> {
+        self.modify(|invites| invites.set_guild_settings(guild_id, settings))
+    }
+
+    fn counted_at(&self, guild_id: u64) -> StoreResult<Option<i64>> {
+        Ok(self.invites().counted_at(guild_id))
+    }
+
+    fn set_counted_at(&self, guild_id: u64, at: i64) -> StoreResult<()> {
+        self.modify(|invites| invites.set_counted_at(guild_id, at))
+    }
+
+    fn record_join(&self, join: &JoinRecord) -> StoreResult<()> {
+        self.modify(|invites| invites.record_join(join))
+    }
+
+    fn record_leave(&self, guild_id: u64, user_id: u64, left_at: i64) -> StoreResult<()> {
+        self.modify(|invites| invites.record_leave(guild_id, user_id, left_at))
+    }
+
+    fn joins(&self, guild_id: u64, filter: &JoinFilter) -> StoreResult<Vec<JoinRecord>> {
+        Ok(self.invites().joins(guild_id, filter))
+    }
+
+    fn reload(&self) -> StoreResult<()> {
+        let file = parse(&fs::read_to_string(&self.path)?)?;
+        *self.invites() = Invites::new(file.invites, file.guilds, file.counted_at, file.joins);
+        Ok(())
+    }
+}
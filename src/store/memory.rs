@@ -0,0 +1,354 @@
+/* Invite mappings held in memory. `Invites` is the bookkeeping behind both
+ * `JsonStore` and `MemoryStore`. `MemoryStore` itself only exists in tests:
+ * the bot must not forget which roles belong to which invite when it
+ * restarts, so `StorageConfig` deliberately offers no way to select it. */
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::sync::RwLock;
+
+#[cfg(test)]
+use super::{MappingStore, StoreResult};
+use super::{GuildSettings, JoinFilter, JoinRecord, LiveInvite, StoredInvite};
+
+/// The invite mappings, keyed (and thereby ordered) by code, along with the
+/// guild settings and join log. Shared by the in-memory and JSON file backends.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Invites {
+    invites: BTreeMap<String, StoredInvite>,
+    guilds: BTreeMap<u64, GuildSettings>,
+    counted_at: BTreeMap<u64, i64>,
+    joins: Vec<JoinRecord>,
+}
+
+impl Invites {
+    pub(super) fn new(
+        invites: Vec<StoredInvite>,
+        guilds: BTreeMap<u64, GuildSettings>,
+        counted_at: BTreeMap<u64, i64>,
+        joins: Vec<JoinRecord>,
+    ) -> Self {
+        Invites {
+            invites: invites.into_iter().map(|inv| (inv.code.clone(), inv)).collect(),
+            guilds,
+            counted_at,
+            joins,
+        }
+    }
+
+    pub(super) fn get(&self, guild_id: u64, code: &str) -> Option<StoredInvite> {
+        self.invites.get(code).filter(|inv| inv.guild_id == guild_id).cloned()
+    }
+
+    pub(super) fn list(&self) -> Vec<StoredInvite> {
+        self.invites.values().cloned().collect()
+    }
+
+    pub(super) fn guilds(&self) -> &BTreeMap<u64, GuildSettings> {
+        &self.guilds
+    }
+
+    /// When the use counts of each guild were last reconciled.
+    pub(super) fn all_counted_at(&self) -> &BTreeMap<u64, i64> {
+        &self.counted_at
+    }
+
+    /// Every recorded join, oldest first.
+    pub(super) fn all_joins(&self) -> &[JoinRecord] {
+        &self.joins
+    }
+
+    pub(super) fn joins(&self, guild_id: u64, filter: &JoinFilter) -> Vec<JoinRecord> {
+        let mut joins = self.joins.iter()
+            .filter(|join| join.guild_id == guild_id && filter.matches(join))
+            .cloned()
+            .collect::<Vec<_>>();
+        // Stable sort, so joins in the same second stay newest first too
+        joins.reverse();
+        joins.sort_by_key(|join| std::cmp::Reverse(join.joined_at));
+        joins
+    }
+
+    pub(super) fn record_join(&mut self, join: &JoinRecord) {
+        self.joins.push(join.clone());
+    }
+
+    pub(super) fn record_leave(&mut self, guild_id: u64, user_id: u64, left_at: i64) {
+        let latest = self.joins.iter_mut()
+            .filter(|join| join.guild_id == guild_id && join.user_id == user_id)
+            .max_by_key(|join| join.joined_at);
+        if let Some(join) = latest {
+            join.left_at = Some(left_at);
+        }
+    }
+
+    pub(super) fn guild_settings(&self, guild_id: u64) -> GuildSettings {
+        self.guilds.get(&guild_id).cloned().unwrap_or_default()
+    }
+
+    pub(super) fn set_guild_settings(&mut self, guild_id: u64, settings: &GuildSettings) {
+        self.guilds.insert(guild_id, settings.clone());
+    }
+
+    pub(super) fn counted_at(&self, guild_id: u64) -> Option<i64> {
+        self.counted_at.get(&guild_id).copied()
+    }
+
+    pub(super) fn set_counted_at(&mut self, guild_id: u64, at: i64) {
+        self.counted_at.insert(guild_id, at);
+    }
+
+    fn entry(&mut self, guild_id: u64, code: &str) -> &mut StoredInvite {
+        self.invites.entry(code.to_string()).or_insert_with(|| StoredInvite {
+            code: code.to_string(),
+            guild_id,
+            ..Default::default()
+        })
+    }
+
+    pub(super) fn upsert_invite(&mut self, guild_id: u64, live: &LiveInvite) {
+        let invite = self.entry(guild_id, &live.code);
+        invite.uses = live.uses;
+        invite.max_uses = live.max_uses;
+        if live.inviter.is_some() {
+            invite.inviter = live.inviter;
+        }
+        invite.created_at.get_or_insert(live.created_at);
+    }
+
+    pub(super) fn save_invite(&mut self, invite: &StoredInvite) {
+        self.invites.insert(invite.code.clone(), invite.clone());
+    }
+
+    pub(super) fn link(&mut self, guild_id: u64, code: &str, roles: &[u64]) -> Vec<u64> {
+        let invite = self.entry(guild_id, code);
+        let mut added = Vec::new();
+        for role in roles {
+            if !invite.roles.contains(role) {
+                invite.roles.push(*role);
+                added.push(*role);
+            }
+        }
+        added
+    }
+
+    pub(super) fn unlink(&mut self, code: &str, roles: Option<&[u64]>) -> Vec<u64> {
+        match self.invites.get_mut(code) {
+            Some(invite) => {
+                let (removed, kept) = invite.roles
+                    .drain(..)
+                    .partition(|r| roles.is_none_or(|roles| roles.contains(r)));
+                invite.roles = kept;
+                removed
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub(super) fn set_label(&mut self, code: &str, label: Option<&str>) {
+        if let Some(invite) = self.invites.get_mut(code) {
+            invite.label = label.map(str::to_string);
+        }
+    }
+
+    pub(super) fn set_note(&mut self, code: &str, note: Option<&str>) {
+        if let Some(invite) = self.invites.get_mut(code) {
+            invite.note = note.map(str::to_string);
+        }
+    }
+
+    pub(super) fn record_uses(&mut self, code: &str, uses: u64) {
+        if let Some(invite) = self.invites.get_mut(code) {
+            invite.uses = uses;
+        }
+    }
+
+    pub(super) fn remove_invite(&mut self, code: &str) {
+        self.invites.remove(code);
+    }
+}
+
+/// A store that only lives as long as the process, for testing code that
+/// works with a `MappingStore` without touching the disk. Not available
+/// outside tests, see the module documentation.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    invites: RwLock<Invites>,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+#[cfg(test)]
+impl MappingStore for MemoryStore {
+    fn get(&self, guild_id: u64, code: &str) -> StoreResult<Option<StoredInvite>> {
+        Ok(self.invites.read().unwrap_or_else(|p| p.into_inner()).get(guild_id, code))
+    }
+
+    fn list(&self) -> StoreResult<Vec<StoredInvite>> {
+        Ok(self.invites.read().unwrap_or_else(|p| p.into_inner()).list())
+    }
+
+    fn upsert_invite(&self, guild_id: u64, invite: &LiveInvite) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).upsert_invite(guild_id, invite);
+        Ok(())
+    }
+
+    fn save_invite(&self, invite: &StoredInvite) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).save_invite(invite);
+        Ok(())
+    }
+
+    fn link(&self, guild_id: u64, code: &str, roles: &[u64]) -> StoreResult<Vec<u64>> {
+        Ok(self.invites.write().unwrap_or_else(|p| p.into_inner()).link(guild_id, code, roles))
+    }
+
+    fn unlink(&self, code: &str, roles: Option<&[u64]>) -> StoreResult<Vec<u64>> {
+        Ok(self.invites.write().unwrap_or_else(|p| p.into_inner()).unlink(code, roles))
+    }
+
+    fn set_label(&self, code: &str, label: Option<&str>) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).set_label(code, label);
+        Ok(())
+    }
+
+    fn set_note(&self, code: &str, note: Option<&str>) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).set_note(code, note);
+        Ok(())
+    }
+
+    fn record_uses(&self, code: &str, uses: u64) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).record_uses(code, uses);
+        Ok(())
+    }
+
+    fn remove_invite(&self, code: &str) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).remove_invite(code);
+        Ok(())
+    }
+
+    fn guild_settings(&self, guild_id: u64) -> StoreResult<GuildSettings> {
+        Ok(self.invites.read().unwrap_or_else(|p| p.into_inner()).guild_settings(guild_id))
+    }
+
+    fn set_guild_settings(&self, guild_id: u64, settings: &GuildSettings) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).set_guild_settings(guild_id, settings);
+        Ok(())
+    }
+
+    fn counted_at(&self, guild_id: u64) -> StoreResult<Option<i64>> {
+        Ok(self.invites.read().unwrap_or_else(|p| p.into_inner()).counted_at(guild_id))
+    }
+
+    fn set_counted_at(&self, guild_id: u64, at: i64) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).set_counted_at(guild_id, at);
+        Ok(())
+    }
+
+    fn record_join(&self, join: &JoinRecord) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).record_join(join);
+        Ok(())
+    }
+
+    fn record_leave(&self, guild_id: u64, user_id: u64, left_at: i64) -> StoreResult<()> {
+        self.invites.write().unwrap_or_else(|p| p.into_inner()).record_leave(guild_id, user_id, left_at);
+        Ok(())
+    }
+
+    fn joins(&self, guild_id: u64, filter: &JoinFilter) -> StoreResult<Vec<JoinRecord>> {
+        Ok(self.invites.read().unwrap_or_else(|p| p.into_inner()).joins(guild_id, filter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Confidence;
+
+    const GUILD: u64 = 1;
+
+    fn live(code: &str, uses: u64) -> LiveInvite {
+        LiveInvite { code: code.to_string(), uses, max_uses: 0, inviter: Some(7), created_at: 100 }
+    }
+
+    fn join(user_id: u64, joined_at: i64) -> JoinRecord {
+        JoinRecord {
+            guild_id: GUILD,
+            user_id,
+            joined_at,
+            invite: Some("abc".to_string()),
+            inviter: None,
+            roles: Vec::new(),
+            confidence: Confidence::Certain,
+            left_at: None,
+        }
+    }
+
+    #[test]
+    fn link_tracks_the_invite_and_returns_new_roles() {
+        let store = MemoryStore::new();
+        assert_eq!(store.link(GUILD, "abc", &[10, 11]).unwrap(), vec![10, 11]);
+        assert_eq!(store.link(GUILD, "abc", &[11, 12]).unwrap(), vec![12]);
+
+        let inv = store.get(GUILD, "abc").unwrap().unwrap();
+        assert_eq!(inv.roles, vec![10, 11, 12]);
+        assert_eq!(inv.guild_id, GUILD);
+        // Invites of other guilds are not found by code alone
+        assert_eq!(store.get(2, "abc").unwrap(), None);
+    }
+
+    #[test]
+    fn unlink_removes_given_roles_or_all() {
+        let store = MemoryStore::new();
+        store.link(GUILD, "abc", &[10, 11, 12]).unwrap();
+        assert_eq!(store.unlink("abc", Some(&[11, 13])).unwrap(), vec![11]);
+        assert_eq!(store.unlink("abc", None).unwrap(), vec![10, 12]);
+        assert!(store.get(GUILD, "abc").unwrap().unwrap().roles.is_empty());
+        assert!(store.unlink("missing", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn upsert_keeps_roles_label_and_creation_date() {
+        let store = MemoryStore::new();
+        store.link(GUILD, "abc", &[10]).unwrap();
+        store.set_label("abc", Some("Summer event")).unwrap();
+        store.upsert_invite(GUILD, &live("abc", 3)).unwrap();
+        store.upsert_invite(GUILD, &LiveInvite { inviter: None, created_at: 200, ..live("abc", 5) }).unwrap();
+
+        let inv = store.get(GUILD, "abc").unwrap().unwrap();
+        assert_eq!((inv.uses, inv.roles, inv.label.as_deref()), (5, vec![10], Some("Summer event")));
+        assert_eq!((inv.inviter, inv.created_at), (Some(7), Some(100)));
+    }
+
+    #[test]
+    fn list_guild_is_ordered_by_code() {
+        let store = MemoryStore::new();
+        store.upsert_invite(GUILD, &live("c", 0)).unwrap();
+        store.upsert_invite(2, &live("b", 0)).unwrap();
+        store.upsert_invite(GUILD, &live("a", 0)).unwrap();
+
+        let codes = store.list_guild(GUILD).unwrap().into_iter().map(|inv| inv.code).collect::<Vec<_>>();
+        assert_eq!(codes, vec!["a", "c"]);
+        assert_eq!(store.list().unwrap().len(), 3);
+
+        store.remove_invite("a").unwrap();
+        assert_eq!(store.list_guild(GUILD).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn leaves_mark_the_latest_join() {
+        let store = MemoryStore::new();
+        store.record_join(&join(5, 10)).unwrap();
+        store.record_join(&join(5, 20)).unwrap();
+        store.record_join(&join(6, 15)).unwrap();
+        store.record_leave(GUILD, 5, 30).unwrap();
+
+        let joins = store.joins(GUILD, &JoinFilter::User(5)).unwrap();
+        assert_eq!(joins.iter().map(|j| (j.joined_at, j.left_at)).collect::<Vec<_>>(), vec![(20, Some(30)), (10, None)]);
+        assert_eq!(store.joins(GUILD, &JoinFilter::All).unwrap().len(), 3);
+    }
+}
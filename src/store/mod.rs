@@ -0,0 +1,458 @@
+/* Persistent storage for the invite -> role mappings.
+ * This replaces the old JSON_PATH file that was rewritten wholesale on every
+ * `sync`. Every change to a mapping is now written to the store as it happens.
+ * Event handlers and commands only talk to the `MappingStore` trait, so the
+ * backend can be swapped out (or replaced by `MemoryStore` in tests). */
+pub mod backup;
+pub mod json;
+pub mod memory;
+pub mod sqlite;
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+pub use json::JsonStore;
+#[cfg(test)]
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+/// An invite as it is known to the store: which guild it belongs to, the
+/// roles linked to it and the last use count we have seen for it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredInvite {
+    pub code: String,
+    pub guild_id: u64,
+    pub roles: Vec<u64>,
+    pub uses: u64,
+    /// How many uses the invite allows, 0 for unlimited.
+    #[serde(default)]
+    pub max_uses: u64,
+    /// The user who created the invite, if known.
+    #[serde(default)]
+    pub inviter: Option<u64>,
+    /// A human-readable name for the invite, e.g. the event it was made for.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Unix timestamp of when the invite was created, if known.
+    #[serde(default)]
+    pub created_at: Option<i64>,
+    /// Free-text notes about the invite.
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// An invite as it currently exists in a guild, according to Discord.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveInvite {
+    pub code: String,
+    pub uses: u64,
+    pub max_uses: u64,
+    pub inviter: Option<u64>,
+    /// Unix timestamp.
+    pub created_at: i64,
+}
+
+/// How sure we are about the invite a member joined through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Confidence {
+    /// The invite was the only one whose use count went up.
+    Certain,
+    /// The invite disappeared after reaching its max uses, and no other
+    /// invite was used.
+    UsedUp,
+    /// Several invites were used at once, we cannot tell which one it was.
+    Ambiguous,
+    /// No invite we know of was used.
+    Unknown,
+}
+
+impl Confidence {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Confidence::Certain => "certain",
+            Confidence::UsedUp => "used_up",
+            Confidence::Ambiguous => "ambiguous",
+            Confidence::Unknown => "unknown",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "certain" => Confidence::Certain,
+            "used_up" => Confidence::UsedUp,
+            "ambiguous" => Confidence::Ambiguous,
+            _ => Confidence::Unknown,
+        }
+    }
+}
+
+/// A member joining a guild, and what we made of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JoinRecord {
+    pub guild_id: u64,
+    pub user_id: u64,
+    /// Unix timestamp, in seconds.
+    pub joined_at: i64,
+    /// The invite the member was attributed to, if any.
+    pub invite: Option<String>,
+    /// The user who created that invite, if known.
+    pub inviter: Option<u64>,
+    /// The roles the member was given because of the invite.
+    pub roles: Vec<u64>,
+    pub confidence: Confidence,
+    /// Unix timestamp of when the member left the guild again, if they did.
+    #[serde(default)]
+    pub left_at: Option<i64>,
+}
+
+/// Which joins to look up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinFilter {
+    All,
+    Invite(String),
+    User(u64),
+}
+
+impl JoinFilter {
+    pub fn matches(&self, join: &JoinRecord) -> bool {
+        match self {
+            JoinFilter::All => true,
+            JoinFilter::Invite(code) => join.invite.as_deref() == Some(code.as_str()),
+            JoinFilter::User(user_id) => join.user_id == *user_id,
+        }
+    }
+}
+
+/// Per-guild settings, editable by each guild's moderators.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    /// Whether linked roles are assigned to members joining this guild.
+    pub auto_assign: bool,
+    /// Channel to report which invite each new member joined through.
+    pub log_channel: Option<u64>,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        GuildSettings { auto_assign: true, log_channel: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// The database failed its integrity check.
+    Corrupt(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(why) => write!(f, "database error: {}", why),
+            StoreError::Io(why) => write!(f, "I/O error: {}", why),
+            StoreError::Json(why) => write!(f, "malformed JSON: {}", why),
+            StoreError::Corrupt(why) => write!(f, "corrupt database: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(why: rusqlite::Error) -> Self {
+        StoreError::Sqlite(why)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(why: std::io::Error) -> Self {
+        StoreError::Io(why)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(why: serde_json::Error) -> Self {
+        StoreError::Json(why)
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Storage for invite -> role mappings, the last known use count of every
+/// tracked invite and per-guild settings. Implementations must make each call
+/// atomic: a failed call leaves the stored mapping as it was.
+pub trait MappingStore: Send + Sync {
+    /// A single invite tracked in `guild_id`, if it is known.
+    fn get(&self, guild_id: u64, code: &str) -> StoreResult<Option<StoredInvite>>;
+
+    /// Every tracked invite across all guilds, ordered by code.
+    fn list(&self) -> StoreResult<Vec<StoredInvite>>;
+
+    /// Every invite tracked in `guild_id`, ordered by code.
+    fn list_guild(&self, guild_id: u64) -> StoreResult<Vec<StoredInvite>> {
+        Ok(self.list()?.into_iter().filter(|inv| inv.guild_id == guild_id).collect())
+    }
+
+    /// Start tracking an invite, or refresh the use counts of a tracked one.
+    /// Linked roles, the label and the note are left untouched.
+    fn upsert_invite(&self, guild_id: u64, invite: &LiveInvite) -> StoreResult<()>;
+
+    /// Track `invite` exactly as given, replacing whatever was stored for its
+    /// code before, roles included.
+    fn save_invite(&self, invite: &StoredInvite) -> StoreResult<()>;
+
+    /// Link `roles` to the invite, tracking it if it is not already.
+    /// Returns the roles that were not linked before.
+    fn link(&self, guild_id: u64, code: &str, roles: &[u64]) -> StoreResult<Vec<u64>>;
+
+    /// Unlink `roles` from the invite, or every role when `roles` is `None`.
+    /// Returns the roles that were actually removed.
+    fn unlink(&self, code: &str, roles: Option<&[u64]>) -> StoreResult<Vec<u64>>;
+
+    /// Set or clear the label of a tracked invite.
+    fn set_label(&self, code: &str, label: Option<&str>) -> StoreResult<()>;
+
+    /// Set or clear the note on a tracked invite.
+    fn set_note(&self, code: &str, note: Option<&str>) -> StoreResult<()>;
+
+    /// Remember the last seen use count of a tracked invite.
+    fn record_uses(&self, code: &str, uses: u64) -> StoreResult<()>;
+
+    /// Forget an invite and every role linked to it.
+    fn remove_invite(&self, code: &str) -> StoreResult<()>;
+
+    /// The settings of a guild, or the defaults if none were ever saved.
+    fn guild_settings(&self, guild_id: u64) -> StoreResult<GuildSettings>;
+
+    /// Save the settings of a guild.
+    fn set_guild_settings(&self, guild_id: u64, settings: &GuildSettings) -> StoreResult<()>;
+
+    /// When the stored use counts of the guild's invites were last brought in
+    /// line with the guild, as a Unix timestamp. Members who joined since and
+    /// are not in the join log were missed.
+    fn counted_at(&self, guild_id: u64) -> StoreResult<Option<i64>>;
+
+    /// Remember when the use counts of the guild's invites were reconciled.
+    fn set_counted_at(&self, guild_id: u64, at: i64) -> StoreResult<()>;
+
+    /// Add a join to the guild's audit log.
+    fn record_join(&self, join: &JoinRecord) -> StoreResult<()>;
+
+    /// Mark the latest join of a user in a guild as having left at `left_at`.
+    fn record_leave(&self, guild_id: u64, user_id: u64, left_at: i64) -> StoreResult<()>;
+
+    /// The joins of a guild matching `filter`, newest first.
+    fn joins(&self, guild_id: u64, filter: &JoinFilter) -> StoreResult<Vec<JoinRecord>>;
+
+    /// Re-read whatever is kept in memory from disk, e.g. after the file was
+    /// edited by hand. Stores that read from disk on every call have nothing
+    /// to do.
+    fn reload(&self) -> StoreResult<()> {
+        Ok(())
+    }
+}
+
+/// Open the store at `path`. Files ending in `.json` are kept as a plain JSON
+/// file, anything else is treated as an SQLite database.
+pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Arc<dyn MappingStore>> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == "json") {
+        Ok(Arc::new(JsonStore::open(path)?))
+    } else {
+        Ok(Arc::new(SqliteStore::open(path)?))
+    }
+}
+
+/// Check that the store at `path` exists and can be read, without changing it.
+pub fn check<P: AsRef<Path>>(path: P) -> StoreResult<()> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == "json") {
+        JsonStore::check(path)
+    } else {
+        SqliteStore::check(path)
+    }
+}
+
+/// Replace the file at `path` with `contents` in one go. See `replace_with`.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace_with(path, |tmp| {
+        let mut file = fs::File::create(tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    })
+}
+
+/// Have `write` write a temporary file next to `path` and sync it to disk,
+/// then rename it over `path`. Whatever happens, `path` holds either the old
+/// or the new contents, never something in between.
+fn replace_with<E: From<io::Error>>(path: &Path, write: impl FnOnce(&Path) -> Result<(), E>) -> Result<(), E> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let _ = fs::remove_file(&tmp);
+    if let Err(why) = write(&tmp).and_then(|_| fs::rename(&tmp, path).map_err(E::from)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(why);
+    }
+
+    // Sync the directory too, or the rename itself may be lost in a crash
+    #[cfg(unix)]
+    {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// An empty directory for a test's files, named after the test.
+#[cfg(test)]
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tcysm-bot-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// What reconciling the invites of a guild changed in the store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Drift {
+    /// Invites that were not tracked yet.
+    pub added: Vec<String>,
+    /// Invites that no longer exist.
+    pub removed: Vec<String>,
+    /// Invites whose use count was out of date: the code, stored count and
+    /// actual count.
+    pub recounted: Vec<(String, u64, u64)>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.recounted.is_empty()
+    }
+}
+
+/// Bring the stored invites of a guild in line with the invites that are
+/// actually active in it: invites that no longer exist are forgotten, and
+/// every active invite is tracked with its current use count. Returns what
+/// had drifted.
+pub fn reconcile(store: &dyn MappingStore, guild_id: u64, active: &[LiveInvite]) -> StoreResult<Drift> {
+    let mut drift = Drift::default();
+    let stored = store.list_guild(guild_id)?;
+    for inv in &stored {
+        if !active.iter().any(|live| live.code == inv.code) {
+            store.remove_invite(&inv.code)?;
+            drift.removed.push(inv.code.clone());
+        }
+    }
+
+    for live in active {
+        match stored.iter().find(|inv| inv.code == live.code) {
+            Some(inv) if inv.uses != live.uses => drift.recounted.push((live.code.clone(), inv.uses, live.uses)),
+            Some(_) => {}
+            None => drift.added.push(live.code.clone()),
+        }
+        store.upsert_invite(guild_id, live)?;
+    }
+
+    Ok(drift)
+}
+
+/// Import a file written by the old JSON_PATH "database". Invites without
+/// any roles carry no guild information (nor anything else worth keeping),
+/// so they are skipped; reconciling with the guild will track them again.
+/// Existing mappings are kept; returns the number of invites imported.
+pub fn import_legacy_json<P: AsRef<Path>>(store: &dyn MappingStore, path: P) -> StoreResult<usize> {
+    let contents = std::fs::read_to_string(path)?;
+    let legacy: Vec<LegacyInviteRoles> = serde_json::from_str(&contents)?;
+
+    let mut imported = 0;
+    for invite in &legacy {
+        if let Some(first) = invite.roles.first() {
+            let roles = invite.roles.iter().map(|r| r.id).collect::<Vec<u64>>();
+            store.link(first.guild_id, &invite.code, &roles)?;
+            imported += 1;
+        }
+    }
+
+    Ok(imported)
+}
+
+/// The format written by the old JSON_PATH "database": a list of invite codes,
+/// each with the full serialised `Role` objects linked to it. Only the fields
+/// we still care about are read, everything else in the role is ignored.
+#[derive(Deserialize, Debug)]
+pub struct LegacyInviteRoles {
+    pub code: String,
+    pub roles: Vec<LegacyRole>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LegacyRole {
+    #[serde(deserialize_with = "snowflake")]
+    pub id: u64,
+    #[serde(deserialize_with = "snowflake")]
+    pub guild_id: u64,
+}
+
+/// Discord (and serenity) serialise IDs as strings, but accept integers too.
+fn snowflake<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Snowflake {
+        Str(String),
+        Int(u64),
+    }
+
+    match Snowflake::deserialize(deserializer)? {
+        Snowflake::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        Snowflake::Int(i) => Ok(i),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: u64 = 1;
+
+    fn live(code: &str, uses: u64) -> LiveInvite {
+        LiveInvite { code: code.to_string(), uses, max_uses: 0, inviter: None, created_at: 100 }
+    }
+
+    #[test]
+    fn reconcile_reports_what_changed_while_offline() {
+        let store = MemoryStore::new();
+        for (code, uses) in [("deleted", 1), ("unchanged", 2), ("used", 3)] {
+            store.upsert_invite(GUILD, &live(code, uses)).unwrap();
+        }
+        store.link(GUILD, "used", &[10]).unwrap();
+        store.upsert_invite(2, &live("other guild", 0)).unwrap();
+
+        let active = [live("created", 0), live("unchanged", 2), live("used", 5)];
+        let drift = reconcile(&store, GUILD, &active).unwrap();
+        assert_eq!(drift, Drift {
+            added: vec!["created".to_string()],
+            removed: vec!["deleted".to_string()],
+            recounted: vec![("used".to_string(), 3, 5)],
+        });
+
+        let codes = store.list_guild(GUILD).unwrap().into_iter().map(|inv| inv.code).collect::<Vec<_>>();
+        assert_eq!(codes, vec!["created", "unchanged", "used"]);
+        let used = store.get(GUILD, "used").unwrap().unwrap();
+        assert_eq!((used.uses, used.roles), (5, vec![10]));
+        assert!(store.get(2, "other guild").unwrap().is_some());
+
+        // Nothing drifts when nothing changed
+        assert!(reconcile(&store, GUILD, &active).unwrap().is_empty());
+    }
+}
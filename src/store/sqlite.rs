@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use tracing::info;
+
+use super::{Confidence, GuildSettings, JoinFilter, JoinRecord, LiveInvite, MappingStore, StoreError, StoreResult, StoredInvite};
+
+/// Schema migrations, applied in order. The index of the last applied
+/// migration + 1 is kept in SQLite's `user_version` pragma, so new
+/// migrations must only ever be appended to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "CREATE TABLE invites (
+        code     TEXT PRIMARY KEY,
+        guild_id INTEGER NOT NULL,
+        uses     INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE invite_roles (
+        code    TEXT NOT NULL REFERENCES invites(code) ON DELETE CASCADE,
+        role_id INTEGER NOT NULL,
+        PRIMARY KEY (code, role_id)
+    );
+    CREATE INDEX invites_guild ON invites(guild_id);",
+    // 2: Per-guild settings
+    "CREATE TABLE guilds (
+        guild_id    INTEGER PRIMARY KEY,
+        auto_assign INTEGER NOT NULL DEFAULT 1,
+        log_channel INTEGER
+    );",
+    // 3: Remember max uses, to tell used up invites from deleted ones
+    "ALTER TABLE invites ADD COLUMN max_uses INTEGER NOT NULL DEFAULT 0;",
+    // 4: Join audit log. Joins outlive the invites they came through, so
+    // `invite` is deliberately not a foreign key.
+    "ALTER TABLE invites ADD COLUMN inviter INTEGER;
+    CREATE TABLE joins (
+        id         INTEGER PRIMARY KEY,
+        guild_id   INTEGER NOT NULL,
+        user_id    INTEGER NOT NULL,
+        joined_at  INTEGER NOT NULL,
+        invite     TEXT,
+        inviter    INTEGER,
+        confidence TEXT NOT NULL
+    );
+    CREATE TABLE join_roles (
+        join_id INTEGER NOT NULL REFERENCES joins(id) ON DELETE CASCADE,
+        role_id INTEGER NOT NULL
+    );
+    CREATE INDEX joins_guild ON joins(guild_id, joined_at);",
+    // 5: Leaves, to tell how many members an invite brought in stayed
+    "ALTER TABLE joins ADD COLUMN left_at INTEGER;",
+    // 6: Labels
+    "ALTER TABLE invites ADD COLUMN label TEXT;",
+    // 7: When each invite was created, and notes about it
+    "ALTER TABLE invites ADD COLUMN created_at INTEGER;
+    ALTER TABLE invites ADD COLUMN note TEXT;",
+    // 8: When the use counts were last reconciled, to find joins missed while offline
+    "ALTER TABLE guilds ADD COLUMN counted_at INTEGER;",
+];
+
+/// The columns `invite_from_row` expects, in order.
+const INVITE_COLUMNS: &str = "code, guild_id, uses, max_uses, inviter, label, created_at, note";
+
+/// SQLite-backed invite store. The connection is behind a mutex so the store
+/// can be shared between the event handlers and commands through an `Arc`.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path` and bring its schema up to date.
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn)?;
+
+        Ok(SqliteStore { conn: Mutex::new(conn) })
+    }
+
+    /// Check that the database at `path` is intact, without changing it.
+    pub fn check<P: AsRef<Path>>(path: P) -> StoreResult<()> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let result: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+        if result != "ok" {
+            return Err(StoreError::Corrupt(result));
+        }
+        conn.query_row("SELECT count(*) FROM invites", [], |row| row.get::<_, i64>(0))?;
+        Ok(())
+    }
+
+    /// Write a consistent copy of the database at `path` to `to`, even while
+    /// the bot has it open.
+    pub fn snapshot(path: &Path, to: &Path) -> StoreResult<()> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        super::replace_with(to, |tmp| {
+            let tmp = tmp.to_str()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not valid UTF-8", tmp.display())))?;
+            conn.execute("VACUUM INTO ?1", params![tmp])?;
+            std::fs::File::open(tmp)?.sync_all()?;
+            Ok(())
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A poisoned lock only means another thread panicked mid-query; SQLite
+        // itself rolls back the unfinished transaction, so keep going.
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MappingStore for SqliteStore {
+    fn list(&self) -> StoreResult<Vec<StoredInvite>> {
+        let conn = self.conn();
+
+        let mut roles = HashMap::<String, Vec<u64>>::new();
+        {
+            let mut stmt = conn.prepare("SELECT code, role_id FROM invite_roles ORDER BY rowid")?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)))?;
+            for row in rows {
+                let (code, role) = row?;
+                roles.entry(code).or_default().push(role);
+            }
+        }
+
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM invites ORDER BY code", INVITE_COLUMNS))?;
+        let rows = stmt.query_map([], invite_from_row)?;
+
+        let mut invites = Vec::new();
+        for row in rows {
+            let mut invite = row?;
+            invite.roles = roles.remove(&invite.code).unwrap_or_default();
+            invites.push(invite);
+        }
+        Ok(invites)
+    }
+
+    fn get(&self, guild_id: u64, code: &str) -> StoreResult<Option<StoredInvite>> {
+        let conn = self.conn();
+        let invite = conn
+            .query_row(
+                &format!("SELECT {} FROM invites WHERE code = ?1 AND guild_id = ?2", INVITE_COLUMNS),
+                params![code, guild_id],
+                invite_from_row,
+            )
+            .optional()?;
+
+        match invite {
+            Some(mut invite) => {
+                let mut stmt = conn.prepare("SELECT role_id FROM invite_roles WHERE code = ?1 ORDER BY rowid")?;
+                let roles = stmt.query_map(params![code], |row| row.get(0))?;
+                invite.roles = roles.collect::<Result<_, _>>()?;
+                Ok(Some(invite))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn upsert_invite(&self, guild_id: u64, invite: &LiveInvite) -> StoreResult<()> {
+        self.conn().execute(
+            "INSERT INTO invites (code, guild_id, uses, max_uses, inviter, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(code) DO UPDATE SET uses = excluded.uses, max_uses = excluded.max_uses,
+                                             inviter = COALESCE(excluded.inviter, inviter),
+                                             created_at = COALESCE(created_at, excluded.created_at)",
+            params![invite.code, guild_id, invite.uses, invite.max_uses, invite.inviter, invite.created_at],
+        )?;
+        Ok(())
+    }
+
+    fn save_invite(&self, invite: &StoredInvite) -> StoreResult<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        // Replacing the row cascades to the roles linked to it
+        tx.execute(
+            "INSERT OR REPLACE INTO invites (code, guild_id, uses, max_uses, inviter, label, created_at, note)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![invite.code, invite.guild_id, invite.uses, invite.max_uses, invite.inviter, invite.label, invite.created_at, invite.note],
+        )?;
+        {
+            let mut stmt = tx.prepare("INSERT OR IGNORE INTO invite_roles (code, role_id) VALUES (?1, ?2)")?;
+            for role in &invite.roles {
+                stmt.execute(params![invite.code, role])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    // All roles are linked in a single transaction, so either all of them are
+    // persisted or none are.
+    fn link(&self, guild_id: u64, code: &str, roles: &[u64]) -> StoreResult<Vec<u64>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO invites (code, guild_id, uses) VALUES (?1, ?2, 0)",
+            params![code, guild_id],
+        )?;
+
+        let mut added = Vec::new();
+        {
+            let mut stmt = tx.prepare("INSERT OR IGNORE INTO invite_roles (code, role_id) VALUES (?1, ?2)")?;
+            for role in roles {
+                if stmt.execute(params![code, role])? > 0 {
+                    added.push(*role);
+                }
+            }
+        }
+        tx.commit()?;
+
+        Ok(added)
+    }
+
+    fn unlink(&self, code: &str, roles: Option<&[u64]>) -> StoreResult<Vec<u64>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        let linked: Vec<u64> = {
+            let mut stmt = tx.prepare("SELECT role_id FROM invite_roles WHERE code = ?1 ORDER BY rowid")?;
+            let rows = stmt.query_map(params![code], |row| row.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        let removed: Vec<u64> = match roles {
+            Some(roles) => linked.into_iter().filter(|r| roles.contains(r)).collect(),
+            None => linked,
+        };
+
+        {
+            let mut stmt = tx.prepare("DELETE FROM invite_roles WHERE code = ?1 AND role_id = ?2")?;
+            for role in &removed {
+                stmt.execute(params![code, role])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(removed)
+    }
+
+    fn set_label(&self, code: &str, label: Option<&str>) -> StoreResult<()> {
+        self.conn().execute("UPDATE invites SET label = ?2 WHERE code = ?1", params![code, label])?;
+        Ok(())
+    }
+
+    fn set_note(&self, code: &str, note: Option<&str>) -> StoreResult<()> {
+        self.conn().execute("UPDATE invites SET note = ?2 WHERE code = ?1", params![code, note])?;
+        Ok(())
+    }
+
+    fn record_uses(&self, code: &str, uses: u64) -> StoreResult<()> {
+        self.conn().execute("UPDATE invites SET uses = ?2 WHERE code = ?1", params![code, uses])?;
+        Ok(())
+    }
+
+    fn remove_invite(&self, code: &str) -> StoreResult<()> {
+        self.conn().execute("DELETE FROM invites WHERE code = ?1", params![code])?;
+        Ok(())
+    }
+
+    fn guild_settings(&self, guild_id: u64) -> StoreResult<GuildSettings> {
+        let settings = self.conn()
+            .query_row("SELECT auto_assign, log_channel FROM guilds WHERE guild_id = ?1", params![guild_id], |row| {
+                Ok(GuildSettings { auto_assign: row.get(0)?, log_channel: row.get(1)? })
+            })
+            .optional()?;
+        Ok(settings.unwrap_or_default())
+    }
+
+    fn set_guild_settings(&self, guild_id: u64, settings: &GuildSettings) -> StoreResult<()> {
+        self.conn().execute(
+            "INSERT INTO guilds (guild_id, auto_assign, log_channel) VALUES (?1, ?2, ?3)
+             ON CONFLICT(guild_id) DO UPDATE SET auto_assign = excluded.auto_assign, log_channel = excluded.log_channel",
+            params![guild_id, settings.auto_assign, settings.log_channel],
+        )?;
+        Ok(())
+    }
+
+    fn counted_at(&self, guild_id: u64) -> StoreResult<Option<i64>> {
+        let counted_at = self.conn()
+            .query_row("SELECT counted_at FROM guilds WHERE guild_id = ?1", params![guild_id], |row| row.get(0))
+            .optional()?;
+        Ok(counted_at.flatten())
+    }
+
+    fn set_counted_at(&self, guild_id: u64, at: i64) -> StoreResult<()> {
+        self.conn().execute(
+            "INSERT INTO guilds (guild_id, counted_at) VALUES (?1, ?2)
+             ON CONFLICT(guild_id) DO UPDATE SET counted_at = excluded.counted_at",
+            params![guild_id, at],
+        )?;
+        Ok(())
+    }
+
+    fn record_join(&self, join: &JoinRecord) -> StoreResult<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO joins (guild_id, user_id, joined_at, invite, inviter, confidence, left_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![join.guild_id, join.user_id, join.joined_at, join.invite, join.inviter, join.confidence.as_str(), join.left_at],
+        )?;
+        let join_id = tx.last_insert_rowid();
+        {
+            let mut stmt = tx.prepare("INSERT INTO join_roles (join_id, role_id) VALUES (?1, ?2)")?;
+            for role in &join.roles {
+                stmt.execute(params![join_id, role])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn record_leave(&self, guild_id: u64, user_id: u64, left_at: i64) -> StoreResult<()> {
+        self.conn().execute(
+            "UPDATE joins SET left_at = ?3 WHERE id = (
+                SELECT id FROM joins WHERE guild_id = ?1 AND user_id = ?2 ORDER BY joined_at DESC, id DESC LIMIT 1
+             )",
+            params![guild_id, user_id, left_at],
+        )?;
+        Ok(())
+    }
+
+    fn joins(&self, guild_id: u64, filter: &JoinFilter) -> StoreResult<Vec<JoinRecord>> {
+        let conn = self.conn();
+        let (clause, value) = match filter {
+            JoinFilter::All => ("", None),
+            JoinFilter::Invite(code) => (" AND invite = ?2", Some(rusqlite::types::Value::Text(code.clone()))),
+            JoinFilter::User(user_id) => (" AND user_id = ?2", Some(rusqlite::types::Value::Integer(*user_id as i64))),
+        };
+        let query = format!(
+            "SELECT id, guild_id, user_id, joined_at, invite, inviter, confidence, left_at FROM joins
+             WHERE guild_id = ?1{} ORDER BY joined_at DESC, id DESC",
+            clause
+        );
+
+        let map_row = |row: &rusqlite::Row<'_>| {
+            Ok((row.get::<_, i64>(0)?, JoinRecord {
+                guild_id: row.get(1)?,
+                user_id: row.get(2)?,
+                joined_at: row.get(3)?,
+                invite: row.get(4)?,
+                inviter: row.get(5)?,
+                roles: Vec::new(),
+                confidence: Confidence::parse(&row.get::<_, String>(6)?),
+                left_at: row.get(7)?,
+            }))
+        };
+        let mut stmt = conn.prepare(&query)?;
+        let rows = match value {
+            Some(value) => stmt.query_map(params![guild_id, value], map_row)?.collect::<Result<Vec<_>, _>>()?,
+            None => stmt.query_map(params![guild_id], map_row)?.collect::<Result<Vec<_>, _>>()?,
+        };
+
+        let mut role_stmt = conn.prepare("SELECT role_id FROM join_roles WHERE join_id = ?1 ORDER BY rowid")?;
+        let mut joins = Vec::with_capacity(rows.len());
+        for (id, mut join) in rows {
+            join.roles = role_stmt.query_map(params![id], |row| row.get(0))?.collect::<Result<_, _>>()?;
+            joins.push(join);
+        }
+        Ok(joins)
+    }
+}
+
+fn invite_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredInvite> {
+    Ok(StoredInvite {
+        code: row.get(0)?,
+        guild_id: row.get(1)?,
+        roles: Vec::new(),
+        uses: row.get(2)?,
+        max_uses: row.get(3)?,
+        inviter: row.get(4)?,
+        label: row.get(5)?,
+        created_at: row.get(6)?,
+        note: row.get(7)?,
+    })
+}
+
+fn migrate(conn: &mut Connection) -> StoreResult<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!(migration = i + 1, "Applying database migration");
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{import_legacy_json, scratch_dir};
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
+
+    fn schema(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY name").unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(&format!("SELECT name FROM pragma_table_info('{}')", table)).unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn in_memory() -> SqliteStore {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+        migrate(&mut conn).unwrap();
+        SqliteStore { conn: Mutex::new(conn) }
+    }
+
+    #[test]
+    fn migrates_an_empty_database_to_the_latest_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(user_version(&conn), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        assert_eq!(columns(&conn, "invites"), ["code", "guild_id", "uses", "max_uses", "inviter", "label", "created_at", "note"]);
+        assert_eq!(columns(&conn, "guilds"), ["guild_id", "auto_assign", "log_channel", "counted_at"]);
+        assert_eq!(columns(&conn, "joins"), ["id", "guild_id", "user_id", "joined_at", "invite", "inviter", "confidence", "left_at"]);
+        assert_eq!(columns(&conn, "invite_roles"), ["code", "role_id"]);
+        assert_eq!(columns(&conn, "join_roles"), ["join_id", "role_id"]);
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let before = schema(&conn);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema(&conn), before);
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrating_an_old_database_keeps_its_invites() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute("INSERT INTO invites (code, guild_id, uses) VALUES ('abc', 1, 3)", []).unwrap();
+        conn.execute("INSERT INTO invite_roles (code, role_id) VALUES ('abc', 20)", []).unwrap();
+
+        migrate(&mut conn).unwrap();
+        let store = SqliteStore { conn: Mutex::new(conn) };
+        let invite = store.get(1, "abc").unwrap().unwrap();
+        assert_eq!((invite.uses, invite.max_uses, invite.roles), (3, 0, vec![20]));
+    }
+
+    #[test]
+    fn imports_legacy_json_with_either_kind_of_snowflake() {
+        let path = scratch_dir("sqlite-legacy").join("invites.json");
+        std::fs::write(&path, r#"[
+            {"code": "strings", "roles": [{"id": "20", "guild_id": "1", "name": "Alumni"}, {"id": "30", "guild_id": "1"}]},
+            {"code": "numbers", "roles": [{"id": 40, "guild_id": 1, "colour": 0}]},
+            {"code": "empty", "roles": []}
+        ]"#).unwrap();
+
+        let store = in_memory();
+        assert_eq!(import_legacy_json(&store, &path).unwrap(), 2);
+        assert_eq!(store.get(1, "strings").unwrap().unwrap().roles, vec![20, 30]);
+        assert_eq!(store.get(1, "numbers").unwrap().unwrap().roles, vec![40]);
+        assert_eq!(store.get(1, "empty").unwrap(), None);
+    }
+}